@@ -7,11 +7,25 @@
 //! Cargo re-run the build script whenever `memory.x` is changed,
 //! updating `memory.x` ensures a rebuild of the application with the
 //! new memory settings.
+//!
+//! It also turns `keymap.toml` into the macro, layer and combo tables of
+//! `src/keypad.rs`.
 
 use std::env;
-use std::fs::File;
+use std::fmt::Write as _;
+use std::fs::{self, File};
 use std::io::Write;
+use std::ops::{Range, RangeInclusive};
 use std::path::PathBuf;
+use std::process;
+
+use toml_edit::{Array, ImDocument, Item, Table, Value};
+
+// Layers are kept in bit masks of a u32, see src/layers.rs
+const MAX_LAYERS: usize = 32;
+// See src/combo.rs and src/leader.rs
+const MAX_COMBO_KEYS: usize = 4;
+const MAX_LEADER_KEYS: usize = 5;
 
 fn main() {
     // Put `memory.x` in our output directory and ensure it's
@@ -23,9 +37,668 @@ fn main() {
         .unwrap();
     println!("cargo:rustc-link-search={}", out.display());
 
+    // Generate the keymap tables included by `src/keypad.rs`
+    let source = fs::read_to_string("keymap.toml").unwrap();
+    let keycodes = fs::read_to_string("src/keycode.rs").unwrap();
+    let keypad = fs::read_to_string("src/keypad.rs").unwrap();
+    let board = Board::new(&fs::read_to_string("src/main.rs").unwrap());
+    let keymap = Keymap::new(&source, &keycodes, &keypad, board).generate();
+    fs::write(out.join("keymap.rs"), keymap).unwrap();
+
     // By default, Cargo will re-run a build script whenever
     // any file in the project changes. By specifying `memory.x`
-    // here, we ensure the build script is only re-run when
-    // `memory.x` is changed.
+    // and the keymap inputs here, we ensure the build script is
+    // only re-run when one of them is changed.
     println!("cargo:rerun-if-changed=memory.x");
+    println!("cargo:rerun-if-changed=keymap.toml");
+    println!("cargo:rerun-if-changed=src/keycode.rs");
+    println!("cargo:rerun-if-changed=src/keypad.rs");
+    println!("cargo:rerun-if-changed=src/main.rs");
+}
+
+// Size of the board, from the constants of src/main.rs
+struct Board {
+    rows: usize,
+    cols: usize,
+    encoders: usize,
+}
+
+impl Board {
+    fn new(main_source: &str) -> Self {
+        // The `const NAME: usize = N;` line of a constant
+        let constant = |name: &str| {
+            let prefix = format!("const {name}: usize = ");
+            main_source
+                .lines()
+                .find_map(|line| line.strip_prefix(&prefix)?.strip_suffix(';'))
+                .and_then(|value| value.parse().ok())
+                .unwrap_or_else(|| panic!("src/main.rs has no `{prefix}N;` line"))
+        };
+
+        Self {
+            rows: constant("ROWS"),
+            cols: constant("COLS"),
+            encoders: constant("ENCODERS"),
+        }
+    }
+}
+
+struct Keymap<'a> {
+    source: &'a str,
+    // Names of the KeyCode variants
+    keycodes: Vec<&'a str>,
+    // Names of the constants of src/keypad.rs, written there or imported
+    constants: Vec<&'a str>,
+    board: Board,
+    // Names of the macros and strings, as written in keymap.toml
+    macros: Vec<String>,
+    // Names of the tap-dance keys, and their `TD(..)` keymap keys
+    dances: Vec<(String, String)>,
+}
+
+impl<'a> Keymap<'a> {
+    fn new(source: &'a str, keycode_source: &'a str, keypad_source: &'a str, board: Board) -> Self {
+        // Variants are the `NAME = 0x..,` lines of the KeyCode enum
+        let keycodes = keycode_source
+            .lines()
+            .filter_map(|line| line.trim().split_once(" = 0x"))
+            .map(|(name, _)| name)
+            .collect();
+        // The `const NAME: ..` and `use path::{NAME, ..};` lines at the top level
+        let constants = keypad_source
+            .lines()
+            .flat_map(|line| {
+                let line = line.strip_prefix("pub ").unwrap_or(line);
+                let names = if let Some(constant) = line.strip_prefix("const ") {
+                    constant.split_once(':').map_or("", |(name, _)| name)
+                } else if let Some(path) = line.strip_prefix("use ") {
+                    let names = path.rsplit("::").next().unwrap_or_default();
+                    names.trim_matches(|c| matches!(c, '{' | '}' | ';'))
+                } else {
+                    ""
+                };
+                names.split(',').map(str::trim)
+            })
+            .filter(|name| {
+                name.starts_with(|c: char| c.is_ascii_uppercase())
+                    && name
+                        .chars()
+                        .all(|c| c.is_ascii_uppercase() || c.is_ascii_digit() || c == '_')
+            })
+            .collect();
+
+        Self {
+            source,
+            keycodes,
+            constants,
+            board,
+            macros: Vec::new(),
+            dances: Vec::new(),
+        }
+    }
+
+    // Print a config error at its line in keymap.toml and stop the build
+    fn error(&self, span: Option<Range<usize>>, message: &str) -> ! {
+        let offset = span.map_or(0, |span| span.start);
+        let before = &self.source[..offset];
+        let line = before.matches('\n').count() + 1;
+        let column = before.len() - before.rfind('\n').map_or(0, |i| i + 1) + 1;
+        eprintln!("error: keymap.toml:{line}:{column}: {message}");
+        process::exit(1);
+    }
+
+    fn generate(mut self) -> String {
+        let document = match ImDocument::parse(self.source) {
+            Ok(document) => document,
+            Err(error) => {
+                eprintln!("error: keymap.toml: {error}");
+                process::exit(1);
+            }
+        };
+
+        let mut code = String::from("// Generated by build.rs from keymap.toml\n\n");
+
+        if let Some(macros) = self.table(&document, "macros") {
+            for (name, item) in macros.iter() {
+                let value = self.value(item, "a macro must be a list");
+                let array = self.array(value, "a macro must be a list");
+                let body = if array.iter().all(|value| value.is_array()) {
+                    format!("Sequence::Presses({})", self.presses(array))
+                } else {
+                    format!("Sequence::Steps({})", self.steps(array))
+                };
+                self.define(&mut code, macros, name, &body);
+            }
+        }
+
+        if let Some(strings) = self.table(&document, "strings") {
+            for (name, item) in strings.iter() {
+                let value = self.value(item, "a string must be a string");
+                let text = self.str(value, "a string must be a string");
+                let body = format!("Sequence::Text(Text::new({text:?}))");
+                self.define(&mut code, strings, name, &body);
+            }
+        }
+
+        let Some(layers) = document.get("layer") else {
+            self.error(None, "missing [[layer]] tables");
+        };
+        let Some(layers) = layers.as_array_of_tables() else {
+            self.error(layers.span(), "`layer` must be a list of [[layer]] tables");
+        };
+        if layers.is_empty() || layers.len() > MAX_LAYERS {
+            self.error(
+                layers.span(),
+                "the keymap must have between 1 and 32 layers",
+            );
+        }
+
+        // Tap-dance keys are written in the grid, they need the layer count
+        if let Some(dances) = self.table(&document, "tap_dances") {
+            for (name, item) in dances.iter() {
+                let Some(dance) = item.as_table_like() else {
+                    self.error(
+                        item.span(),
+                        "a tap-dance key must be a table, e.g. { taps = [...], holds = [...] }",
+                    );
+                };
+                let list = |field: &str| match dance.get(field) {
+                    Some(item) => {
+                        let message = format!("`{field}` must be a list of keys");
+                        let keys = self.array(self.value(item, &message), &message);
+                        let keys: Vec<String> = keys
+                            .iter()
+                            .map(|key| {
+                                let name = self.str(key, &message);
+                                self.key_name(name, key, layers.len())
+                            })
+                            .collect();
+                        keys.join(", ")
+                    }
+                    None => String::new(),
+                };
+                let (taps, holds) = (list("taps"), list("holds"));
+                if taps.is_empty() && holds.is_empty() {
+                    self.error(item.span(), "a tap-dance key needs `taps` or `holds`");
+                }
+
+                self.check_name(dances, name);
+                let key = format!("TD([{taps}], [{holds}])");
+                self.dances.push((name.to_string(), key));
+            }
+        }
+
+        code.push_str("pub const KEYMAP: Keymap = &[\n");
+        for layer in layers.iter() {
+            let Some(rows) = layer.get("rows") else {
+                self.error(layer.span(), "missing `rows` in [[layer]]");
+            };
+            let rows = self.array(
+                self.value(rows, "`rows` must be a list"),
+                "`rows` must be a list",
+            );
+            if rows.len() != self.board.rows {
+                self.error(
+                    rows.span(),
+                    &format!(
+                        "a layer must have {} rows, ROWS in src/main.rs",
+                        self.board.rows
+                    ),
+                );
+            }
+
+            // A chord sent before the macro of every key of the layer
+            let prefix = match layer.get("prefix") {
+                Some(prefix) => {
+                    let message = "`prefix` must be a chord, e.g. \"C(B)\"";
+                    let prefix = self.value(prefix, message);
+                    match self.chord(self.str(prefix, message)) {
+                        Some(chord) => format!("Some(&chord!({chord}))"),
+                        None => self.error(prefix.span(), message),
+                    }
+                }
+                None => "None".to_string(),
+            };
+
+            writeln!(code, "    Layer {{\n        prefix: {prefix},").unwrap();
+            code.push_str("        keys: keymap! {\n");
+            for row in rows.iter() {
+                let keys = self.array(row, "a row must be a list of key names");
+                if keys.len() != self.board.cols {
+                    self.error(
+                        row.span(),
+                        &format!(
+                            "a row must have {} keys, COLS in src/main.rs",
+                            self.board.cols
+                        ),
+                    );
+                }
+                let keys: Vec<String> =
+                    keys.iter().map(|key| self.key(key, layers.len())).collect();
+                writeln!(code, "            [{}],", keys.join(", ")).unwrap();
+            }
+            code.push_str("        },\n");
+
+            // The [clockwise, counter-clockwise] keys of each encoder
+            let mut encoders = Vec::new();
+            if let Some(item) = layer.get("encoders") {
+                let message = "`encoders` must be a list of [clockwise, counter-clockwise] keys";
+                let value = self.value(item, message);
+                let list = self.array(value, message);
+                if list.len() > self.board.encoders {
+                    self.error(
+                        value.span(),
+                        &format!(
+                            "a layer has keys for at most {} encoders, ENCODERS in src/main.rs",
+                            self.board.encoders
+                        ),
+                    );
+                }
+                for steps in list.iter() {
+                    let steps = self.array(steps, message);
+                    if steps.len() != 2 {
+                        self.error(steps.span(), message);
+                    }
+                    let steps: Vec<String> = steps
+                        .iter()
+                        .map(|key| {
+                            let name = self.str(key, message);
+                            if name.starts_with("TH(")
+                                || self.dances.iter().any(|(dance, _)| dance == name)
+                            {
+                                self.error(
+                                    key.span(),
+                                    "an encoder step cannot be a tap-hold or tap-dance key",
+                                );
+                            }
+                            let key = self.key_name(name, key, layers.len());
+                            format!("keymap_key!({key})")
+                        })
+                        .collect();
+                    encoders.push(format!("[{}]", steps.join(", ")));
+                }
+            }
+            writeln!(
+                code,
+                "        encoders: &[{}],\n    }},",
+                encoders.join(", ")
+            )
+            .unwrap();
+        }
+        code.push_str("];\n\n");
+
+        code.push_str("pub const COMBOS: &[Combo] = &[\n");
+        if let Some(combos) = document.get("combo") {
+            let Some(combos) = combos.as_array_of_tables() else {
+                self.error(combos.span(), "`combo` must be a list of [[combo]] tables");
+            };
+            for combo in combos.iter() {
+                let keys = self.positions(combo, "combo", 2..=MAX_COMBO_KEYS);
+                let Some(key) = combo.get("key") else {
+                    self.error(combo.span(), "missing `key` in [[combo]]");
+                };
+                let key = self.value(key, "`key` must be a key name");
+                let name = self.str(key, "`key` must be a key name");
+                let action = self.key_name(name, key, layers.len());
+                writeln!(
+                    code,
+                    "    Combo {{ keys: &[{keys}], action: keymap_key!({action}) }},"
+                )
+                .unwrap();
+            }
+        }
+        code.push_str("];\n\n");
+
+        code.push_str("pub const LEADER_SEQUENCES: &[LeaderSequence] = &[\n");
+        if let Some(sequences) = document.get("leader") {
+            let Some(sequences) = sequences.as_array_of_tables() else {
+                self.error(
+                    sequences.span(),
+                    "`leader` must be a list of [[leader]] tables",
+                );
+            };
+            for sequence in sequences.iter() {
+                let keys = self.positions(sequence, "leader sequence", 1..=MAX_LEADER_KEYS);
+                let sequence = self.sequence(sequence);
+                writeln!(
+                    code,
+                    "    LeaderSequence {{ keys: &[{keys}], sequence: {sequence} }},"
+                )
+                .unwrap();
+            }
+        }
+        code.push_str("];\n");
+
+        code
+    }
+
+    // The macro of a leader sequence: a macro, a string or a chord
+    fn sequence(&self, table: &Table) -> String {
+        let message = "`key` must name a macro, a string or a chord";
+        let Some(key) = table.get("key") else {
+            self.error(table.span(), "missing `key` in [[leader]]");
+        };
+        let key = self.value(key, message);
+        let name = self.str(key, message);
+        if self.macros.iter().any(|macro_name| macro_name == name) {
+            return name.to_uppercase();
+        }
+        match self.chord(name) {
+            Some(chord) => format!("Sequence::Presses(&[&chord!({chord})])"),
+            None => self.error(key.span(), message),
+        }
+    }
+
+    // The keys of a combo or leader sequence, as `(row, col)` positions
+    fn positions(&self, table: &Table, kind: &str, count: RangeInclusive<usize>) -> String {
+        let message = "`keys` must be a list of [row, col] positions";
+        let Some(keys) = table.get("keys") else {
+            self.error(table.span(), &format!("missing `keys` in the {kind}"));
+        };
+        let keys = self.array(self.value(keys, message), message);
+        if !count.contains(&keys.len()) {
+            let (min, max) = count.into_inner();
+            self.error(
+                keys.span(),
+                &format!("a {kind} must have between {min} and {max} keys"),
+            );
+        }
+
+        let mut positions = Vec::new();
+        for key in keys.iter() {
+            let position = self.array(key, message);
+            let position: Option<Vec<i64>> = position
+                .iter()
+                .map(|n| n.as_integer().filter(|n| (0..=u8::MAX as i64).contains(n)))
+                .collect();
+            let Some(&[row, col]) = position.as_deref() else {
+                self.error(key.span(), message);
+            };
+            if row as usize >= self.board.rows || col as usize >= self.board.cols {
+                let (rows, cols) = (self.board.rows, self.board.cols);
+                self.error(
+                    key.span(),
+                    &format!("[{row}, {col}] is off the board of {rows} rows and {cols} columns"),
+                );
+            }
+            let position = format!("({row}, {col})");
+            // Keys are pressed together in a combo, one after the other in a sequence
+            if kind == "combo" && positions.contains(&position) {
+                self.error(key.span(), "a combo holds the same key twice");
+            }
+            positions.push(position);
+        }
+        positions.join(", ")
+    }
+
+    fn define(&mut self, code: &mut String, table: &Table, name: &str, body: &str) {
+        self.check_name(table, name);
+        self.macros.push(name.to_string());
+
+        let constant = name.to_uppercase();
+        writeln!(
+            code,
+            "#[allow(unused)]\npub const {constant}: Sequence = {body};\n"
+        )
+        .unwrap();
+    }
+
+    // Names of macros, strings and tap-dance keys share one namespace
+    fn check_name(&self, table: &Table, name: &str) {
+        let span = || table.get_key_value(name).and_then(|(key, _)| key.span());
+        let valid = name.starts_with(|c: char| c.is_ascii_lowercase())
+            && name
+                .chars()
+                .all(|c| c.is_ascii_lowercase() || c.is_ascii_digit() || c == '_');
+        if !valid {
+            self.error(
+                span(),
+                &format!("`{name}` must be lowercase letters, digits and `_`"),
+            );
+        }
+        // Keywords of the layers, a macro of the same name could not be used
+        if matches!(name, "trans" | "record" | "play" | "leader") {
+            self.error(span(), &format!("`{name}` is a reserved key name"));
+        }
+        let defined = self.macros.iter().any(|defined| defined == name)
+            || self.dances.iter().any(|(defined, _)| defined == name);
+        if defined {
+            self.error(span(), &format!("`{name}` is already defined"));
+        }
+        let constant = name.to_uppercase();
+        if self.constants.contains(&constant.as_str()) {
+            self.error(
+                span(),
+                &format!("`{name}` is taken by the `{constant}` constant of src/keypad.rs"),
+            );
+        }
+        // Tables generated next to the macros
+        if matches!(name, "keymap" | "combos" | "leader_sequences") {
+            self.error(
+                span(),
+                &format!("`{name}` is taken by the generated `{constant}` table"),
+            );
+        }
+    }
+
+    fn table<'d>(&self, document: &'d ImDocument<&str>, name: &str) -> Option<&'d Table> {
+        let item = document.get(name)?;
+        match item.as_table() {
+            Some(table) => Some(table),
+            None => self.error(item.span(), &format!("`{name}` must be a table")),
+        }
+    }
+
+    fn value<'v>(&self, item: &'v Item, message: &str) -> &'v Value {
+        match item.as_value() {
+            Some(value) => value,
+            None => self.error(item.span(), message),
+        }
+    }
+
+    fn array<'v>(&self, value: &'v Value, message: &str) -> &'v Array {
+        match value.as_array() {
+            Some(array) => array,
+            None => self.error(value.span(), message),
+        }
+    }
+
+    fn str<'v>(&self, value: &'v Value, message: &str) -> &'v str {
+        match value.as_str() {
+            Some(text) => text,
+            None => self.error(value.span(), message),
+        }
+    }
+
+    // A chord: a list of KeyCode names
+    fn press(&self, value: &Value) -> String {
+        let keys = self.array(value, "keys must be a list of key names");
+        let keys: Vec<String> = keys
+            .iter()
+            .map(|key| {
+                let name = self.str(key, "a key must be a KeyCode name");
+                if !self.keycodes.contains(&name) {
+                    self.error(key.span(), &format!("unknown key code `{name}`"));
+                }
+                format!("KeyCode::{name}")
+            })
+            .collect();
+        format!("&[{}]", keys.join(", "))
+    }
+
+    fn presses(&self, array: &Array) -> String {
+        let presses: Vec<String> = array.iter().map(|press| self.press(press)).collect();
+        format!("&[{}]", presses.join(", "))
+    }
+
+    fn steps(&self, array: &Array) -> String {
+        let steps: Vec<String> = array.iter().map(|step| self.step(step)).collect();
+        format!("&[{}]", steps.join(", "))
+    }
+
+    fn step(&self, value: &Value) -> String {
+        let Some(step) = value.as_inline_table() else {
+            self.error(
+                value.span(),
+                "a step must be an inline table, e.g. { tap = [\"A\"] }",
+            );
+        };
+        let field = |name: &str| step.get(name);
+        let nested = || match field("steps") {
+            Some(steps) => self.steps(self.array(steps, "`steps` must be a list of steps")),
+            None => self.error(step.span(), "missing `steps`"),
+        };
+
+        if let Some(keys) = field("down") {
+            format!("Step::Down({})", self.press(keys))
+        } else if let Some(keys) = field("up") {
+            format!("Step::Up({})", self.press(keys))
+        } else if let Some(keys) = field("tap") {
+            format!("Step::Tap({})", self.press(keys))
+        } else if let Some(text) = field("text") {
+            let text = self.str(text, "`text` must be a string");
+            format!("Step::Text(Text::new({text:?}))")
+        } else if let Some(c) = field("unicode") {
+            let text = self.str(c, "`unicode` must be a string");
+            let mut chars = text.chars();
+            match (chars.next(), chars.next()) {
+                (Some(c), None) => format!("Step::Unicode({c:?})"),
+                _ => self.error(c.span(), "`unicode` must be a single character"),
+            }
+        } else if let Some(mode) = field("unicode_mode") {
+            let name = self.str(mode, "`unicode_mode` must be a string");
+            match name {
+                "Linux" | "WinCompose" | "WindowsAltCodes" | "MacOs" => {
+                    format!("Step::UnicodeMode(UnicodeMode::{name})")
+                }
+                _ => self.error(mode.span(), &format!("unknown Unicode mode `{name}`")),
+            }
+        } else if let Some(ms) = field("wait") {
+            match ms
+                .as_integer()
+                .filter(|&ms| (0..=u32::MAX as i64).contains(&ms))
+            {
+                Some(ms) => format!("Step::Wait({ms})"),
+                None => self.error(ms.span(), "`wait` must be a number of milliseconds"),
+            }
+        } else if let Some(count) = field("repeat") {
+            match count
+                .as_integer()
+                .filter(|&n| (0..=u16::MAX as i64).contains(&n))
+            {
+                Some(count) => format!("Step::Repeat({count}, {})", nested()),
+                None => self.error(count.span(), "`repeat` must be a count"),
+            }
+        } else if let Some(keys) = field("hold") {
+            format!("Step::Hold({}, {})", self.press(keys), nested())
+        } else {
+            self.error(step.span(), "unknown step")
+        }
+    }
+
+    // An entry of the keymap grid, written as a `keymap!` key
+    fn key(&self, key: &Value, layers: usize) -> String {
+        let name = self.str(key, "a key must name a macro, a string, a chord or a layer");
+        // A tap-hold key is made of two keys, but not of other tap-hold keys
+        if let Some(args) = name
+            .strip_prefix("TH(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            let Some((hold, tap)) = split_args(args) else {
+                self.error(key.span(), &format!("`{name}` must be TH(hold, tap)"));
+            };
+            let hold = self.key_name(hold.trim(), key, layers);
+            let tap = self.key_name(tap.trim(), key, layers);
+            return format!("TH({hold}, {tap})");
+        }
+        if let Some((_, dance)) = self.dances.iter().find(|(dance, _)| dance == name) {
+            return dance.clone();
+        }
+        self.key_name(name, key, layers)
+    }
+
+    fn key_name(&self, name: &str, key: &Value, layers: usize) -> String {
+        if name.starts_with("TH(") || self.dances.iter().any(|(dance, _)| dance == name) {
+            self.error(
+                key.span(),
+                &format!("`{name}` cannot be part of a tap-hold or tap-dance key"),
+            );
+        }
+        if let Some(layer) = self.layer_key(name) {
+            if layer >= layers {
+                self.error(
+                    key.span(),
+                    &format!("`{name}` points to a layer that does not exist"),
+                );
+            }
+            return name.to_string();
+        }
+        if let Some(keys) = name
+            .strip_prefix("H(")
+            .and_then(|rest| rest.strip_suffix(')'))
+        {
+            if !self.keycodes.contains(&keys) && self.chord(keys).is_none() {
+                self.error(key.span(), &format!("unknown key or chord in `{name}`"));
+            }
+            return name.to_string();
+        }
+        match name {
+            "" => "_".to_string(),
+            "trans" => "Trans".to_string(),
+            "record" => "Record".to_string(),
+            "play" => "Play".to_string(),
+            "leader" => "Leader".to_string(),
+            _ if self.macros.iter().any(|macro_name| macro_name == name) => name.to_uppercase(),
+            _ => match self.chord(name) {
+                Some(chord) => chord.to_string(),
+                None => self.error(
+                    key.span(),
+                    &format!("unknown macro, string, chord or layer key `{name}`"),
+                ),
+            },
+        }
+    }
+
+    // The layer of a layer key such as MO(1)
+    fn layer_key(&self, name: &str) -> Option<usize> {
+        let (kind, rest) = name.split_once('(')?;
+        if !matches!(kind, "MO" | "TG" | "OSL" | "DF") {
+            return None;
+        }
+        let layer = rest.strip_suffix(')')?;
+        // Written as is in the generated code, so only plain digits
+        if layer.is_empty() || !layer.chars().all(|c| c.is_ascii_digit()) {
+            return None;
+        }
+        layer.parse().ok()
+    }
+
+    // A chord such as C(S(T)), checked here so errors point to keymap.toml
+    fn chord<'n>(&self, chord: &'n str) -> Option<&'n str> {
+        let mut inner = chord;
+        while let Some((modifier, rest)) = inner.split_once('(') {
+            if !matches!(modifier, "C" | "S" | "A" | "G" | "RA" | "K") {
+                return None;
+            }
+            inner = rest.strip_suffix(')')?;
+        }
+        (inner != chord && self.keycodes.contains(&inner)).then_some(chord)
+    }
+}
+
+// Split `a, b` at its comma, outside of parentheses
+fn split_args(args: &str) -> Option<(&str, &str)> {
+    let mut depth = 0;
+    for (i, c) in args.char_indices() {
+        match c {
+            '(' => depth += 1,
+            ')' => depth -= 1,
+            ',' if depth == 0 => {
+                let (first, second) = (&args[..i], &args[i + 1..]);
+                return (!second.contains(',')).then_some((first, second));
+            }
+            _ => {}
+        }
+    }
+    None
 }
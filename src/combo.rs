@@ -0,0 +1,210 @@
+// Combos: keys pressed together within a term do the action of the combo
+// instead of their own. Presses wait at the front of the event queue while
+// they could still be the start of a combo. A combo is released with the
+// first of its keys released, the releases of the others do nothing.
+use heapless::Vec;
+
+use crate::event::{EventQueue, KeyEvent};
+use crate::keypad::{Action, Combo, Key};
+
+// Keys of the largest combo
+pub const MAX_COMBO_KEYS: usize = 4;
+
+pub struct Combos<const ROWS: usize, const COLS: usize> {
+    combos: &'static [Combo],
+    term_us: u64,
+    // Combo each held key is part of, until one of its keys is released
+    combo_of: [[Option<usize>; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Combos<ROWS, COLS> {
+    pub fn new(combos: &'static [Combo], term_us: u64) -> Self {
+        Self {
+            combos,
+            term_us,
+            combo_of: [[None; COLS]; ROWS],
+        }
+    }
+
+    // The combo pressed by the events at the front of the queue, and how many
+    // events it is made of. The largest combo wins, keys have to be pressed
+    // one after the other with nothing in between.
+    // Returns None while more presses could still make a combo.
+    pub fn find(&self, events: &EventQueue, now: u64) -> Option<Option<(usize, usize)>> {
+        let Some(first) = events.front() else {
+            return Some(None);
+        };
+        let deadline = first.timestamp + self.term_us;
+
+        let mut pressed: Vec<Key, MAX_COMBO_KEYS> = Vec::new();
+        let mut found = None;
+        for event in events.iter() {
+            if !event.pressed || event.timestamp >= deadline || pressed.contains(&key(event)) {
+                return Some(found);
+            }
+            if pressed.push(key(event)).is_err() {
+                return Some(found);
+            }
+
+            // Combos that hold every key pressed so far
+            let mut candidates = self
+                .combos
+                .iter()
+                .enumerate()
+                .filter(|(_, combo)| pressed.iter().all(|key| combo.keys.contains(key)))
+                .peekable();
+            if candidates.peek().is_none() {
+                return Some(found);
+            }
+
+            let mut larger = false;
+            for (index, combo) in candidates {
+                if combo.keys.len() == pressed.len() {
+                    found = Some((index, pressed.len()));
+                } else {
+                    larger = true;
+                }
+            }
+            if !larger {
+                return Some(found);
+            }
+        }
+
+        if now >= deadline {
+            Some(found)
+        } else {
+            None
+        }
+    }
+
+    // Hold the keys of a combo found, and get its action
+    pub fn press(&mut self, index: usize) -> Action {
+        let combo = self.combos[index];
+        for &(row, col) in combo.keys {
+            self.combo_of[row as usize][col as usize] = Some(index);
+        }
+        combo.action
+    }
+
+    // The action of the combo released by an event of one of its keys,
+    // None if the key is not part of a held combo
+    pub fn release(&mut self, event: &KeyEvent) -> Option<Action> {
+        let index = self
+            .combo_of
+            .get_mut(event.row as usize)?
+            .get_mut(event.col as usize)?
+            .take()?;
+        let combo = self.combos[index];
+        for &(row, col) in combo.keys {
+            self.combo_of[row as usize][col as usize] = None;
+        }
+        Some(combo.action)
+    }
+}
+
+fn key(event: &KeyEvent) -> Key {
+    (event.row, event.col)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const COMBOS: &[Combo] = &[
+        Combo {
+            keys: &[(0, 0), (0, 1)],
+            action: Action::Record,
+        },
+        Combo {
+            keys: &[(0, 0), (0, 1), (0, 2)],
+            action: Action::Play,
+        },
+        Combo {
+            keys: &[(1, 0), (1, 1)],
+            action: Action::Record,
+        },
+    ];
+
+    const TERM: u64 = 50;
+
+    fn find_in(events: &[(u8, u8, bool, u64)], now: u64) -> Option<Option<(usize, usize)>> {
+        let mut queue = EventQueue::new();
+        for &(row, col, pressed, timestamp) in events {
+            queue
+                .push_back(KeyEvent {
+                    row,
+                    col,
+                    pressed,
+                    timestamp,
+                })
+                .unwrap();
+        }
+        Combos::<2, 3>::new(COMBOS, TERM).find(&queue, now)
+    }
+
+    fn event(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            row,
+            col,
+            pressed,
+            timestamp: 0,
+        }
+    }
+
+    #[test]
+    fn keys_pressed_together_make_a_combo() {
+        assert_eq!(
+            find_in(&[(1, 1, true, 0), (1, 0, true, 10)], 10),
+            Some(Some((2, 2)))
+        );
+    }
+
+    #[test]
+    fn presses_wait_while_a_combo_can_form() {
+        assert_eq!(find_in(&[(1, 0, true, 0)], 49), None);
+        assert_eq!(find_in(&[(1, 0, true, 0)], 50), Some(None));
+        // A larger combo could still form
+        assert_eq!(find_in(&[(0, 0, true, 0), (0, 1, true, 10)], 20), None);
+        assert_eq!(
+            find_in(&[(0, 0, true, 0), (0, 1, true, 10)], 50),
+            Some(Some((0, 2)))
+        );
+        assert_eq!(
+            find_in(&[(0, 0, true, 0), (0, 1, true, 10), (0, 2, true, 20)], 20),
+            Some(Some((1, 3)))
+        );
+    }
+
+    #[test]
+    fn other_events_break_a_combo() {
+        // Too late, released first, or another key
+        assert_eq!(
+            find_in(&[(1, 0, true, 0), (1, 1, true, 50)], 50),
+            Some(None)
+        );
+        assert_eq!(
+            find_in(&[(1, 0, true, 0), (1, 0, false, 10), (1, 1, true, 20)], 20),
+            Some(None)
+        );
+        assert_eq!(
+            find_in(&[(1, 0, true, 0), (2, 0, true, 10)], 10),
+            Some(None)
+        );
+        assert_eq!(find_in(&[(2, 0, true, 0)], 0), Some(None));
+    }
+
+    #[test]
+    fn combo_is_released_with_its_first_key() {
+        let mut combos: Combos<2, 3> = Combos::new(COMBOS, TERM);
+        assert!(matches!(combos.press(2), Action::Record));
+
+        assert!(combos.release(&event(0, 0, false)).is_none());
+        assert!(matches!(
+            combos.release(&event(1, 1, false)),
+            Some(Action::Record)
+        ));
+        // The release of the other key is left to the layers, where it does nothing
+        assert!(combos.release(&event(1, 0, false)).is_none());
+        assert!(combos.release(&event(4, 4, false)).is_none());
+    }
+}
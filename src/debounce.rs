@@ -0,0 +1,200 @@
+// Debounce the raw matrix states returned by the scanner.
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DebounceAlgorithm {
+    // Report a change as soon as it is seen, then ignore the key
+    // for the debounce time. Lowest latency, but noise can trigger a press.
+    EagerPerKey,
+    // Report a change once the key has been stable for the debounce time.
+    DeferredPerKey,
+    // Report the changes of a whole row once none of its keys moved
+    // for the debounce time. Cheaper on memory, same delay on press and release.
+    SymmetricPerRow,
+}
+
+pub struct Debouncer<const R: usize, const C: usize> {
+    algorithm: DebounceAlgorithm,
+    debounce_us: u64,
+    // States reported to the rest of the firmware
+    debounced: [[bool; C]; R],
+    // Last raw states seen by the deferred algorithms
+    raw: [[bool; C]; R],
+    // Eager: end of the lockout of each key
+    // Deferred: time of the last raw change of each key
+    key_times: [[u64; C]; R],
+    // Time of the last raw change in each row
+    row_times: [u64; R],
+}
+
+impl<const R: usize, const C: usize> Debouncer<R, C> {
+    pub fn new(algorithm: DebounceAlgorithm, debounce_us: u64) -> Self {
+        Self {
+            algorithm,
+            debounce_us,
+            debounced: [[false; C]; R],
+            raw: [[false; C]; R],
+            key_times: [[0; C]; R],
+            row_times: [0; R],
+        }
+    }
+
+    // Feed a raw scan taken at `now`, and get the debounced states back
+    pub fn update(&mut self, scanned: &[[bool; C]; R], now: u64) -> &[[bool; C]; R] {
+        match self.algorithm {
+            DebounceAlgorithm::EagerPerKey => self.update_eager_per_key(scanned, now),
+            DebounceAlgorithm::DeferredPerKey => self.update_deferred_per_key(scanned, now),
+            DebounceAlgorithm::SymmetricPerRow => self.update_symmetric_per_row(scanned, now),
+        }
+
+        &self.debounced
+    }
+
+    fn update_eager_per_key(&mut self, scanned: &[[bool; C]; R], now: u64) {
+        for (r, row) in scanned.iter().enumerate() {
+            for (c, pressed) in row.iter().enumerate() {
+                let locked_until = &mut self.key_times[r][c];
+
+                if *pressed != self.debounced[r][c] && now >= *locked_until {
+                    self.debounced[r][c] = *pressed;
+                    *locked_until = now + self.debounce_us;
+                }
+            }
+        }
+    }
+
+    fn update_deferred_per_key(&mut self, scanned: &[[bool; C]; R], now: u64) {
+        for (r, row) in scanned.iter().enumerate() {
+            for (c, pressed) in row.iter().enumerate() {
+                if *pressed != self.raw[r][c] {
+                    self.raw[r][c] = *pressed;
+                    self.key_times[r][c] = now;
+                }
+
+                if now - self.key_times[r][c] >= self.debounce_us {
+                    self.debounced[r][c] = self.raw[r][c];
+                }
+            }
+        }
+    }
+
+    fn update_symmetric_per_row(&mut self, scanned: &[[bool; C]; R], now: u64) {
+        for (r, row) in scanned.iter().enumerate() {
+            if *row != self.raw[r] {
+                self.raw[r] = *row;
+                self.row_times[r] = now;
+            }
+
+            if now - self.row_times[r] >= self.debounce_us {
+                self.debounced[r] = self.raw[r];
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const DEBOUNCE_US: u64 = 5_000;
+    const SCAN_PERIOD_US: u64 = 1_000;
+
+    // Feed a trace of single key levels, one per scan period,
+    // and collect the debounced level after each scan
+    fn run_trace(algorithm: DebounceAlgorithm, trace: &[bool]) -> Vec<bool> {
+        let mut debouncer = Debouncer::<1, 1>::new(algorithm, DEBOUNCE_US);
+
+        trace
+            .iter()
+            .enumerate()
+            .map(|(i, level)| {
+                let now = 10_000 + i as u64 * SCAN_PERIOD_US;
+                debouncer.update(&[[*level]], now)[0][0]
+            })
+            .collect()
+    }
+
+    // Count the rising edges seen after debouncing
+    fn presses(levels: &[bool]) -> usize {
+        levels.windows(2).filter(|w| !w[0] && w[1]).count() + usize::from(levels[0])
+    }
+
+    const BOUNCY_PRESS: &[bool] = &[
+        false, true, false, true, false, true, true, true, true, true, true, true, true,
+    ];
+    const BOUNCY_RELEASE: &[bool] = &[
+        true, true, true, false, true, false, true, false, false, false, false, false, false,
+    ];
+
+    #[test]
+    fn eager_reports_press_on_first_edge() {
+        let levels = run_trace(DebounceAlgorithm::EagerPerKey, BOUNCY_PRESS);
+        assert!(levels[1]);
+        assert_eq!(presses(&levels), 1);
+        assert!(levels[1..].iter().all(|l| *l));
+    }
+
+    #[test]
+    fn deferred_waits_for_stable_level() {
+        let levels = run_trace(DebounceAlgorithm::DeferredPerKey, BOUNCY_PRESS);
+        // Last bounce at scan 5, stable for 5 ms at scan 10
+        assert!(levels[..10].iter().all(|l| !*l));
+        assert!(levels[10..].iter().all(|l| *l));
+    }
+
+    #[test]
+    fn bouncy_release_is_reported_once() {
+        for algorithm in [
+            DebounceAlgorithm::EagerPerKey,
+            DebounceAlgorithm::DeferredPerKey,
+            DebounceAlgorithm::SymmetricPerRow,
+        ] {
+            let mut trace = BOUNCY_PRESS.to_vec();
+            trace.extend_from_slice(BOUNCY_RELEASE);
+            let levels = run_trace(algorithm, &trace);
+
+            assert_eq!(presses(&levels), 1, "{:?}", algorithm);
+            assert_eq!(levels.last(), Some(&false), "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn deferred_ignores_short_glitch() {
+        let mut trace = vec![false; 4];
+        trace.extend_from_slice(&[true, true]);
+        trace.extend_from_slice(&[false; 10]);
+
+        for algorithm in [
+            DebounceAlgorithm::DeferredPerKey,
+            DebounceAlgorithm::SymmetricPerRow,
+        ] {
+            let levels = run_trace(algorithm, &trace);
+            assert_eq!(presses(&levels), 0, "{:?}", algorithm);
+        }
+    }
+
+    #[test]
+    fn per_row_waits_for_the_whole_row() {
+        let mut debouncer = Debouncer::<1, 2>::new(DebounceAlgorithm::SymmetricPerRow, DEBOUNCE_US);
+
+        // Key 0 settles right away, key 1 keeps bouncing for 3 ms
+        debouncer.update(&[[true, true]], 0);
+        debouncer.update(&[[true, false]], 1_000);
+        debouncer.update(&[[true, true]], 3_000);
+
+        assert_eq!(debouncer.update(&[[true, true]], 7_000), &[[false, false]]);
+        assert_eq!(debouncer.update(&[[true, true]], 8_000), &[[true, true]]);
+    }
+
+    #[test]
+    fn per_key_debounces_keys_independently() {
+        let mut debouncer = Debouncer::<1, 2>::new(DebounceAlgorithm::DeferredPerKey, DEBOUNCE_US);
+
+        debouncer.update(&[[true, true]], 0);
+        debouncer.update(&[[true, false]], 1_000);
+        debouncer.update(&[[true, true]], 3_000);
+
+        assert_eq!(debouncer.update(&[[true, true]], 5_000), &[[true, false]]);
+        assert_eq!(debouncer.update(&[[true, true]], 8_000), &[[true, true]]);
+    }
+}
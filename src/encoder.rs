@@ -0,0 +1,134 @@
+use embedded_hal::digital::v2::InputPin;
+use rp2040_hal::gpio::DynPin;
+
+use crate::keypad::Key;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Direction {
+    Clockwise,
+    CounterClockwise,
+}
+
+// Position change for each (previous AB, current AB) transition,
+// invalid transitions where both channels changed count as no movement
+const TRANSITIONS: [i8; 16] = [0, -1, 1, 0, 1, 0, 0, -1, -1, 0, 0, 1, 0, 1, -1, 0];
+
+// Quadrature decoder for one encoder
+pub struct Decoder {
+    // Number of transitions making one step, 4 for one step
+    // per detent on most encoders, 8 for one step every two detents
+    resolution: i8,
+    state: u8,
+    count: i8,
+}
+
+impl Decoder {
+    pub fn new(resolution: i8) -> Self {
+        Self {
+            resolution,
+            state: 0b11,
+            count: 0,
+        }
+    }
+
+    // Feed the current level of both channels, get a step once
+    // enough transitions went in the same direction
+    pub fn update(&mut self, a: bool, b: bool) -> Option<Direction> {
+        let state = (u8::from(a) << 1) | u8::from(b);
+        self.count += TRANSITIONS[usize::from(self.state << 2 | state)];
+        self.state = state;
+
+        if self.count >= self.resolution {
+            self.count = 0;
+            Some(Direction::Clockwise)
+        } else if self.count <= -self.resolution {
+            self.count = 0;
+            Some(Direction::CounterClockwise)
+        } else {
+            None
+        }
+    }
+}
+
+// Encoder with its common pin to ground, each step acts as a tap
+// of the key position it is bound to
+pub struct Encoder {
+    a: DynPin,
+    b: DynPin,
+    decoder: Decoder,
+    // Key positions for (clockwise, counter-clockwise) steps
+    keys: (Key, Key),
+}
+
+impl Encoder {
+    pub fn new(mut a: DynPin, mut b: DynPin, resolution: i8, keys: (Key, Key)) -> Self {
+        a.into_pull_up_input();
+        b.into_pull_up_input();
+
+        Self {
+            a,
+            b,
+            decoder: Decoder::new(resolution),
+            keys,
+        }
+    }
+
+    // Read the encoder, and get the key position to tap if it moved one step
+    pub fn poll(&mut self) -> Option<Key> {
+        let a = self.a.is_high().unwrap();
+        let b = self.b.is_high().unwrap();
+
+        match self.decoder.update(a, b)? {
+            Direction::Clockwise => Some(self.keys.0),
+            Direction::CounterClockwise => Some(self.keys.1),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // One detent of a typical encoder, both channels high at rest
+    const CLOCKWISE: [(bool, bool); 4] =
+        [(false, true), (false, false), (true, false), (true, true)];
+
+    fn run(decoder: &mut Decoder, trace: impl Iterator<Item = (bool, bool)>) -> Vec<Direction> {
+        trace.filter_map(|(a, b)| decoder.update(a, b)).collect()
+    }
+
+    #[test]
+    fn one_step_per_detent() {
+        let mut decoder = Decoder::new(4);
+
+        let steps = run(&mut decoder, CLOCKWISE.iter().copied());
+        assert_eq!(steps, [Direction::Clockwise]);
+
+        let steps = run(
+            &mut decoder,
+            CLOCKWISE
+                .iter()
+                .rev()
+                .skip(1)
+                .copied()
+                .chain([(true, true)]),
+        );
+        assert_eq!(steps, [Direction::CounterClockwise]);
+    }
+
+    #[test]
+    fn resolution_groups_detents() {
+        let mut decoder = Decoder::new(8);
+
+        let steps = run(&mut decoder, CLOCKWISE.iter().cycle().take(8).copied());
+        assert_eq!(steps, [Direction::Clockwise]);
+    }
+
+    #[test]
+    fn bouncing_contact_does_not_step() {
+        let mut decoder = Decoder::new(4);
+
+        let trace = [(false, true), (true, true), (false, true), (true, true)];
+        assert!(run(&mut decoder, trace.iter().copied()).is_empty());
+    }
+}
@@ -0,0 +1,91 @@
+use heapless::Deque;
+
+const EVENT_QUEUE_SIZE: usize = 32;
+
+// A key changing state at a given position of the matrix
+// The timestamp is in microseconds since boot, as returned by the RP2040
+// timer, like all the timestamps of the firmware
+#[derive(Clone, Copy, Debug, PartialEq, Eq, defmt::Format)]
+pub struct KeyEvent {
+    pub row: u8,
+    pub col: u8,
+    pub pressed: bool,
+    pub timestamp: u64,
+}
+
+// Key events waiting to be handled by the main loop
+pub type EventQueue = Deque<KeyEvent, EVENT_QUEUE_SIZE>;
+
+// Compare a scanned matrix with the previous state of every key
+// and queue one event per key that changed.
+// A key whose event does not fit in the queue keeps its previous state,
+// so the change is picked up again on the next scan.
+pub fn queue_changes<const R: usize, const C: usize>(
+    states: &mut [[bool; C]; R],
+    scanned: &[[bool; C]; R],
+    timestamp: u64,
+    queue: &mut EventQueue,
+) {
+    for (r, (states, scanned)) in states.iter_mut().zip(scanned.iter()).enumerate() {
+        for (c, (state, pressed)) in states.iter_mut().zip(scanned.iter()).enumerate() {
+            if state == pressed {
+                continue;
+            }
+
+            let event = KeyEvent {
+                row: r as u8,
+                col: c as u8,
+                pressed: *pressed,
+                timestamp,
+            };
+
+            if queue.push_back(event).is_ok() {
+                *state = *pressed;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn change_that_does_not_fit_is_queued_on_the_next_scan() {
+        let mut queue = EventQueue::new();
+        let mut states = [[false; 2]; 1];
+        let scanned = [[true, true]];
+
+        // Room left for one event only
+        for _ in 1..EVENT_QUEUE_SIZE {
+            queue
+                .push_back(KeyEvent {
+                    row: 0,
+                    col: 0,
+                    pressed: false,
+                    timestamp: 0,
+                })
+                .unwrap();
+        }
+        queue_changes(&mut states, &scanned, 10, &mut queue);
+        assert!(queue.is_full());
+        assert_eq!(states, [[true, false]]);
+        assert_eq!(
+            queue.back().map(|event| (event.col, event.pressed)),
+            Some((0, true))
+        );
+
+        queue.clear();
+        queue_changes(&mut states, &scanned, 20, &mut queue);
+        assert_eq!(states, [[true, true]]);
+        assert_eq!(
+            queue.iter().copied().collect::<Vec<_>>(),
+            [KeyEvent {
+                row: 0,
+                col: 1,
+                pressed: true,
+                timestamp: 20
+            }]
+        );
+    }
+}
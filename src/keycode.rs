@@ -1,5 +1,6 @@
 #[repr(u8)]
 #[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub enum KeyCode {
     NONE = 0x00,
 
@@ -86,6 +87,21 @@ pub enum KeyCode {
     LEFT = 0x50,
     DOWN = 0x51,
     UP = 0x52,
+
+    KPPLUS = 0x57,
+    KP1 = 0x59,
+    KP2 = 0x5A,
+    KP3 = 0x5B,
+    KP4 = 0x5C,
+    KP5 = 0x5D,
+    KP6 = 0x5E,
+    KP7 = 0x5F,
+    KP8 = 0x60,
+    KP9 = 0x61,
+    KP0 = 0x62,
+
+    // The extra key left of Z on ISO keyboards
+    KEY102ND = 0x64,
     MUTE = 0x7F,
     VOLUMEUP = 0x80,
     VOLUMEDOWN = 0x81,
@@ -107,3 +123,13 @@ pub enum KeyCode {
     MEDIA_MUTE = 0xEF,
     MEDIA_STOP = 0xF3,
 }
+
+impl KeyCode {
+    // Modifiers are sent as bits of the report modifier byte rather than as keycodes
+    pub const fn modifier_bit(self) -> Option<u8> {
+        match self as u8 {
+            code @ 0xE0..=0xE7 => Some(1 << (code - 0xE0)),
+            _ => None,
+        }
+    }
+}
@@ -1,4 +1,10 @@
+use crate::combo::MAX_COMBO_KEYS;
 use crate::keycode::KeyCode;
+use crate::layers::MAX_LAYERS;
+use crate::leader::MAX_LEADER_KEYS;
+use crate::text::Text;
+use crate::unicode::UnicodeMode;
+use crate::{COLS, ENCODERS, ROWS};
 
 // Represents a Key position in the matrix
 pub type Key = (u8, u8);
@@ -10,25 +16,459 @@ pub type Press = &'static [KeyCode];
 // A list of presses to execute
 pub type Macro = &'static [Press];
 
-// The macro matrix:
-// Macro at (0,0) is triggered by key (0,0), etc.
-pub type MacroMatrix = &'static [Macro];
+// One step of a macro, for macros that need more than a list of taps
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Step {
+    // Press keys and keep them held
+    Down(Press),
+    // Release keys
+    Up(Press),
+    // Press then release keys
+    Tap(Press),
+    // Type a string, one tap per character
+    Text(Text),
+    // Type a Unicode character through the input method of the host
+    Unicode(char),
+    // Change the input method used to type Unicode characters
+    UnicodeMode(UnicodeMode),
+    // Wait a number of milliseconds
+    Wait(u32),
+    // Play steps a number of times
+    Repeat(u16, Steps),
+    // Hold keys (usually modifiers) while the steps play, then release them
+    Hold(Press, Steps),
+}
 
+// A list of steps to execute
+pub type Steps = &'static [Step];
 
-// Define all of our macros
-pub const TMUX_LEAD: Press = &[KeyCode::LEFTCTRL, KeyCode::B];
-pub const TMUX_NEXT: Press = &[KeyCode::N];
-pub const TMUX_PREV: Press = &[KeyCode::P];
+// What a key plays: a plain list of presses, each tapped in turn,
+// a list of steps, a string to type or a Unicode character
+#[allow(unused)]
+#[derive(Clone, Copy)]
+pub enum Sequence {
+    Presses(Macro),
+    Steps(Steps),
+    Text(Text),
+    Unicode(char),
+}
 
-pub const TMUX_NEXT_MACRO: Macro = &[TMUX_LEAD, TMUX_NEXT];
-pub const TMUX_PREV_MACRO: Macro = &[TMUX_LEAD, TMUX_PREV];
+impl From<Macro> for Sequence {
+    fn from(presses: Macro) -> Self {
+        Sequence::Presses(presses)
+    }
+}
 
-// Map macro actions to key matrix
-#[rustfmt::skip]
-pub const MACRO_MATRIX: MacroMatrix = &[
-    TMUX_PREV_MACRO, TMUX_NEXT_MACRO //, Key2, Key3,
-    //Key4, Key5, Key6, Key7,
-    //Key8, Key9, Key10, Key11,
-    //Key12, Key13, Key14, Key15,
-];
+impl From<Steps> for Sequence {
+    fn from(steps: Steps) -> Self {
+        Sequence::Steps(steps)
+    }
+}
 
+// What a key does
+#[derive(Clone, Copy)]
+pub enum Action {
+    // Nothing
+    None,
+    // The action of the next active layer down
+    Transparent,
+    // Layer active while the key is held (MO)
+    Momentary(u8),
+    // Layer switched on or off on each press (TG)
+    Toggle(u8),
+    // Layer active for the next key press only (OSL)
+    OneShot(u8),
+    // Layer used when no other layer is active (DF)
+    Default(u8),
+    // Play a macro when pressed
+    Macro(Sequence),
+    // Keys held down while the key is held, such as a modifier
+    Keys(Press),
+    // One action when tapped and another when held, see src/tap_hold.rs
+    TapHold(&'static TapHold),
+    // Actions chosen by the number of taps, see src/tap_hold.rs
+    TapDance(&'static TapDance),
+    // Start recording key events, or stop the recording in progress
+    Record,
+    // Replay the last recording
+    Play,
+    // Start a leader sequence, see src/leader.rs
+    Leader,
+}
+
+// The actions of a tap-hold key, such as a macro on tap and Ctrl on hold
+#[derive(Clone, Copy)]
+pub struct TapHold {
+    pub tap: Action,
+    pub hold: Action,
+}
+
+// The actions of a tap-dance key by number of taps: taps[0] when tapped once,
+// taps[1] when tapped twice, ..., holds[0] when held, holds[1] when tapped
+// then held, ...
+#[derive(Clone, Copy)]
+pub struct TapDance {
+    pub taps: &'static [Action],
+    pub holds: &'static [Action],
+}
+
+// Keys pressed together that do their own action instead of theirs,
+// whatever the layer
+#[derive(Clone, Copy)]
+pub struct Combo {
+    pub keys: &'static [Key],
+    pub action: Action,
+}
+
+// Keys entered after the leader key, and the macro they play
+#[derive(Clone, Copy)]
+pub struct LeaderSequence {
+    pub keys: &'static [Key],
+    pub sequence: Sequence,
+}
+
+// The macro matrix of a layer, the same shape as the board:
+// Action at [0][0] is triggered by key (0,0), etc.
+pub type MacroMatrix = [[Action; COLS]; ROWS];
+
+// A layer of the keymap. Keys of a prefix layer send its prefix chord,
+// such as Ctrl+B for tmux, before their macro.
+#[derive(Clone, Copy)]
+pub struct Layer<const R: usize, const C: usize> {
+    pub prefix: Option<Press>,
+    pub keys: [[Action; C]; R],
+    // [clockwise, counter-clockwise] actions of each encoder,
+    // encoders past the end of the list are transparent
+    pub encoders: &'static [[Action; 2]],
+}
+
+// The layers of the keymap, from the lowest to the highest
+pub type Keymap = &'static [Layer<ROWS, COLS>];
+
+// A boot keyboard report holds 6 keycodes besides the modifiers
+const MAX_PRESS_KEYCODES: usize = 6;
+
+const fn check_press(press: Press) {
+    let mut keycodes = 0;
+    let mut i = 0;
+    while i < press.len() {
+        if press[i].modifier_bit().is_none() {
+            keycodes += 1;
+        }
+        i += 1;
+    }
+    if keycodes > MAX_PRESS_KEYCODES {
+        panic!("a press holds more than 6 keys besides modifiers");
+    }
+}
+
+const fn check_steps(steps: Steps) {
+    let mut i = 0;
+    while i < steps.len() {
+        match steps[i] {
+            Step::Down(press) | Step::Up(press) | Step::Tap(press) => check_press(press),
+            Step::Repeat(_, steps) => check_steps(steps),
+            Step::Hold(press, steps) => {
+                check_press(press);
+                check_steps(steps);
+            }
+            _ => {}
+        }
+        i += 1;
+    }
+}
+
+// Fail the build if a macro holds a press that cannot be sent,
+// or a layer key points to a layer that does not exist
+const fn check_action(action: Action, layers: usize) {
+    match action {
+        Action::Macro(Sequence::Presses(presses)) => {
+            let mut i = 0;
+            while i < presses.len() {
+                check_press(presses[i]);
+                i += 1;
+            }
+        }
+        Action::Macro(Sequence::Steps(steps)) => check_steps(steps),
+        Action::Keys(press) => check_press(press),
+        Action::TapHold(keys) => {
+            check_action(keys.tap, layers);
+            check_action(keys.hold, layers);
+        }
+        Action::TapDance(keys) => {
+            check_actions(keys.taps, layers);
+            check_actions(keys.holds, layers);
+        }
+        Action::Momentary(layer)
+        | Action::Toggle(layer)
+        | Action::OneShot(layer)
+        | Action::Default(layer)
+            if layer as usize >= layers =>
+        {
+            panic!("a layer key points to a layer that does not exist");
+        }
+        _ => {}
+    }
+}
+
+const fn check_actions(actions: &[Action], layers: usize) {
+    let mut i = 0;
+    while i < actions.len() {
+        check_action(actions[i], layers);
+        i += 1;
+    }
+}
+
+const fn check_matrix(matrix: &MacroMatrix, layers: usize) {
+    let mut row = 0;
+    while row < ROWS {
+        let mut col = 0;
+        while col < COLS {
+            check_action(matrix[row][col], layers);
+            col += 1;
+        }
+        row += 1;
+    }
+}
+
+// Encoder steps are taps, they cannot wait for a tap-hold or tap-dance decision
+const fn check_encoders(encoders: &[[Action; 2]], layers: usize) {
+    if encoders.len() > ENCODERS {
+        panic!("a layer has actions for more encoders than ENCODERS");
+    }
+    let mut i = 0;
+    while i < encoders.len() {
+        check_actions(&encoders[i], layers);
+        let mut step = 0;
+        while step < 2 {
+            if let Action::TapHold(_) | Action::TapDance(_) = encoders[i][step] {
+                panic!("an encoder step cannot be a tap-hold or tap-dance key");
+            }
+            step += 1;
+        }
+        i += 1;
+    }
+}
+
+const fn check_keymap(keymap: Keymap) {
+    if keymap.is_empty() || keymap.len() > MAX_LAYERS {
+        panic!("the keymap must have between 1 and 32 layers");
+    }
+    let mut layer = 0;
+    while layer < keymap.len() {
+        if let Some(prefix) = keymap[layer].prefix {
+            check_press(prefix);
+        }
+        check_matrix(&keymap[layer].keys, keymap.len());
+        check_encoders(keymap[layer].encoders, keymap.len());
+        layer += 1;
+    }
+}
+
+// Fail the build if a combo has keys off the board, or cannot be pressed
+const fn check_combos(combos: &[Combo], layers: usize) {
+    let mut i = 0;
+    while i < combos.len() {
+        let keys = combos[i].keys;
+        if keys.len() < 2 || keys.len() > MAX_COMBO_KEYS {
+            panic!("a combo must have between 2 and 4 keys");
+        }
+        let mut k = 0;
+        while k < keys.len() {
+            if keys[k].0 as usize >= ROWS || keys[k].1 as usize >= COLS {
+                panic!("a combo key is off the board");
+            }
+            k += 1;
+        }
+        check_action(combos[i].action, layers);
+        i += 1;
+    }
+}
+
+// Fail the build if a leader sequence has keys off the board, or a macro
+// that cannot be sent
+const fn check_leader_sequences(sequences: &[LeaderSequence]) {
+    let mut i = 0;
+    while i < sequences.len() {
+        let keys = sequences[i].keys;
+        if keys.is_empty() || keys.len() > MAX_LEADER_KEYS {
+            panic!("a leader sequence must have between 1 and 5 keys");
+        }
+        let mut k = 0;
+        while k < keys.len() {
+            if keys[k].0 as usize >= ROWS || keys[k].1 as usize >= COLS {
+                panic!("a leader sequence key is off the board");
+            }
+            k += 1;
+        }
+        check_action(Action::Macro(sequences[i].sequence), 0);
+        i += 1;
+    }
+}
+
+// The keys of a chord, written with modifier wrappers:
+// C(B) is Ctrl+B, C(S(T)) is Ctrl+Shift+T, K(A) is A alone.
+// S is Shift, A is Alt, G is GUI (Windows/Command), RA is Right Alt (AltGr).
+macro_rules! chord {
+    (@keys [$($key:expr),*] C($($inner:tt)*)) => {
+        chord!(@keys [$($key,)* $crate::keycode::KeyCode::LEFTCTRL] $($inner)*)
+    };
+    (@keys [$($key:expr),*] S($($inner:tt)*)) => {
+        chord!(@keys [$($key,)* $crate::keycode::KeyCode::LEFTSHIFT] $($inner)*)
+    };
+    (@keys [$($key:expr),*] A($($inner:tt)*)) => {
+        chord!(@keys [$($key,)* $crate::keycode::KeyCode::LEFTALT] $($inner)*)
+    };
+    (@keys [$($key:expr),*] G($($inner:tt)*)) => {
+        chord!(@keys [$($key,)* $crate::keycode::KeyCode::LEFTMETA] $($inner)*)
+    };
+    (@keys [$($key:expr),*] RA($($inner:tt)*)) => {
+        chord!(@keys [$($key,)* $crate::keycode::KeyCode::RIGHTALT] $($inner)*)
+    };
+    (@keys [$($key:expr),*] K($code:ident)) => {
+        [$($key,)* $crate::keycode::KeyCode::$code]
+    };
+    (@keys [$($key:expr),*] $code:ident) => {
+        [$($key,)* $crate::keycode::KeyCode::$code]
+    };
+    ($($chord:tt)*) => {
+        chord!(@keys [] $($chord)*)
+    };
+}
+
+// One position of a keymap grid:
+// _ for nothing, Trans for the layer below, Record, Play or Leader,
+// a layer key MO(n), TG(n), OSL(n) or DF(n), keys held with the key such as
+// H(LEFTCTRL), a tap-hold key TH(hold, tap) made of two of these, a tap-dance
+// key TD([taps, ...], [holds, ...]) made of lists of them,
+// a chord tapped once such as C(B), or the name of a Sequence constant
+macro_rules! keymap_key {
+    (_) => {
+        $crate::keypad::Action::None
+    };
+    (Trans) => {
+        $crate::keypad::Action::Transparent
+    };
+    (MO($layer:literal)) => {
+        $crate::keypad::Action::Momentary($layer)
+    };
+    (TG($layer:literal)) => {
+        $crate::keypad::Action::Toggle($layer)
+    };
+    (OSL($layer:literal)) => {
+        $crate::keypad::Action::OneShot($layer)
+    };
+    (DF($layer:literal)) => {
+        $crate::keypad::Action::Default($layer)
+    };
+    (H($($chord:tt)*)) => {
+        $crate::keypad::Action::Keys(&chord!($($chord)*))
+    };
+    (TH($hold:tt $(($($hold_args:tt)*))?, $tap:tt $(($($tap_args:tt)*))?)) => {
+        $crate::keypad::Action::TapHold(&$crate::keypad::TapHold {
+            tap: keymap_key!($tap $(($($tap_args)*))?),
+            hold: keymap_key!($hold $(($($hold_args)*))?),
+        })
+    };
+    (TD(
+        [$($tap:tt $(($($tap_args:tt)*))?),* $(,)?],
+        [$($hold:tt $(($($hold_args:tt)*))?),* $(,)?]
+    )) => {
+        $crate::keypad::Action::TapDance(&$crate::keypad::TapDance {
+            taps: &[$(keymap_key!($tap $(($($tap_args)*))?)),*],
+            holds: &[$(keymap_key!($hold $(($($hold_args)*))?)),*],
+        })
+    };
+    (Record) => {
+        $crate::keypad::Action::Record
+    };
+    (Play) => {
+        $crate::keypad::Action::Play
+    };
+    (Leader) => {
+        $crate::keypad::Action::Leader
+    };
+    ($modifier:ident ($($chord:tt)*)) => {
+        $crate::keypad::Action::Macro($crate::keypad::Sequence::Presses(&[&chord!(
+            $modifier($($chord)*)
+        )]))
+    };
+    ($sequence:ident) => {
+        $crate::keypad::Action::Macro($sequence)
+    };
+}
+
+// A keymap written row by row in the shape of the board:
+//
+// keymap! {
+//     [C(B), ALT_TAB_TAB, _, Record],
+//     ...
+// }
+//
+// A grid that is not ROWS x COLS does not build.
+macro_rules! keymap {
+    ($([$($key:tt $(($($chord:tt)*))?),* $(,)?]),* $(,)?) => {
+        [$([$(keymap_key!($key $(($($chord)*))?)),*]),*]
+    };
+}
+
+// Macros, strings, the layers of the keymap, the combos and the leader
+// sequences, generated from keymap.toml
+include!(concat!(env!("OUT_DIR"), "/keymap.rs"));
+const _: () = check_keymap(KEYMAP);
+const _: () = check_combos(COMBOS, KEYMAP.len());
+const _: () = check_leader_sequences(LEADER_SEQUENCES);
+
+// Rotary encoder steps act as taps of their own key positions, one row past
+// the board per encoder: (clockwise, counter-clockwise) are columns 0 and 1.
+// Their actions are the `encoders` of the layers.
+pub const fn encoder_keys(encoder: usize) -> (Key, Key) {
+    let row = (ROWS + encoder) as u8;
+    ((row, 0), (row, 1))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn chords_nest_modifiers() {
+        assert_eq!(
+            chord!(C(S(T))),
+            [KeyCode::LEFTCTRL, KeyCode::LEFTSHIFT, KeyCode::T]
+        );
+        assert_eq!(chord!(K(A)), [KeyCode::A]);
+    }
+
+    #[test]
+    fn keymap_is_written_row_by_row() {
+        const NEXT: Sequence = Sequence::Presses(&[&[KeyCode::N]]);
+        const GRID: [[Action; 2]; 3] = keymap! {
+            [C(B), NEXT],
+            [MO(1), Record],
+            [TH(H(LEFTCTRL), K(ESC)), TD([NEXT, C(B)], [MO(1)])],
+        };
+        assert!(matches!(
+            GRID[0][0],
+            Action::Macro(Sequence::Presses(&[&[KeyCode::LEFTCTRL, KeyCode::B]]))
+        ));
+        assert!(matches!(GRID[0][1], Action::Macro(Sequence::Presses(_))));
+        assert!(matches!(GRID[1][0], Action::Momentary(1)));
+        assert!(matches!(GRID[1][1], Action::Record));
+        assert!(matches!(
+            GRID[2][0],
+            Action::TapHold(TapHold {
+                tap: Action::Macro(Sequence::Presses(&[&[KeyCode::ESC]])),
+                hold: Action::Keys(&[KeyCode::LEFTCTRL]),
+            })
+        ));
+        assert!(matches!(
+            GRID[2][1],
+            Action::TapDance(TapDance {
+                taps: &[Action::Macro(_), Action::Macro(_)],
+                holds: &[Action::Momentary(1)],
+            })
+        ));
+    }
+}
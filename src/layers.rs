@@ -0,0 +1,291 @@
+// Resolve key events to actions through a stack of layers.
+// The highest active layer with a non transparent action at a position wins,
+// down to the default layer. A key is released on the layer it was pressed on,
+// even if the active layers changed while it was held.
+// Encoder steps are keys on the rows past the board, see `encoder_keys`.
+use crate::event::KeyEvent;
+use crate::keypad::{Action, Layer, Press};
+use crate::ENCODERS;
+
+// Layers are kept in bit masks
+pub const MAX_LAYERS: usize = 32;
+
+pub struct Layers<const ROWS: usize, const COLS: usize> {
+    keymap: &'static [Layer<ROWS, COLS>],
+    default: u8,
+    // Momentary keys holding each layer, and layers toggled on
+    momentary: [u8; MAX_LAYERS],
+    toggled: u32,
+    // Layer active for the next key press only
+    one_shot: Option<u8>,
+    // Layer each held key was pressed on, then each held encoder step
+    pressed_on: [[Option<u8>; COLS]; ROWS],
+    encoders_pressed_on: [[Option<u8>; 2]; ENCODERS],
+}
+
+impl<const ROWS: usize, const COLS: usize> Layers<ROWS, COLS> {
+    pub fn new(keymap: &'static [Layer<ROWS, COLS>]) -> Self {
+        Self {
+            keymap,
+            default: 0,
+            momentary: [0; MAX_LAYERS],
+            toggled: 0,
+            one_shot: None,
+            pressed_on: [[None; COLS]; ROWS],
+            encoders_pressed_on: [[None; 2]; ENCODERS],
+        }
+    }
+
+    // The action of a key event, after updating the layers if it is a layer key.
+    // Positions outside of the keymap do nothing.
+    pub fn event(&mut self, event: &KeyEvent) -> Action {
+        let (row, col) = (event.row as usize, event.col as usize);
+        let on_board = match row.checked_sub(ROWS) {
+            None => col < COLS,
+            Some(encoder) => encoder < ENCODERS && col < 2,
+        };
+        if !on_board {
+            return Action::None;
+        }
+
+        let layer = if event.pressed {
+            let layer = self.layer_at(row, col);
+            *self.pressed_on_mut(row, col) = Some(layer);
+            layer
+        } else {
+            match self.pressed_on_mut(row, col).take() {
+                Some(layer) => layer,
+                None => return Action::None,
+            }
+        };
+
+        let action = self.action(layer, row, col);
+        self.apply(action, event.pressed);
+        action
+    }
+
+    // Prefix of the layer a held key was pressed on
+    pub fn prefix(&self, row: u8, col: u8) -> Option<Press> {
+        let (row, col) = (row as usize, col as usize);
+        let layer = match row.checked_sub(ROWS) {
+            None => self.pressed_on[row].get(col)?,
+            Some(encoder) => self.encoders_pressed_on.get(encoder)?.get(col)?,
+        };
+        self.keymap[(*layer)? as usize].prefix
+    }
+
+    // Update the layers for an action pressed or released.
+    // Also used for the actions tap-hold keys are decided as.
+    pub fn apply(&mut self, action: Action, pressed: bool) {
+        match (action, pressed) {
+            // A layer stays active until every key holding it is released
+            (Action::Momentary(layer), true) => {
+                let held = &mut self.momentary[layer as usize];
+                *held = held.saturating_add(1);
+            }
+            (Action::Momentary(layer), false) => {
+                let held = &mut self.momentary[layer as usize];
+                *held = held.saturating_sub(1);
+            }
+            (Action::Toggle(layer), true) => self.toggled ^= 1 << layer,
+            (Action::OneShot(layer), true) => self.one_shot = Some(layer),
+            (Action::Default(layer), true) => self.default = layer,
+            // Any other key press uses up the one-shot layer
+            (_, true) => self.one_shot = None,
+            _ => {}
+        }
+    }
+
+    fn pressed_on_mut(&mut self, row: usize, col: usize) -> &mut Option<u8> {
+        match row.checked_sub(ROWS) {
+            None => &mut self.pressed_on[row][col],
+            Some(encoder) => &mut self.encoders_pressed_on[encoder][col],
+        }
+    }
+
+    // Action at a position of a layer, encoders missing from a layer are transparent
+    fn action(&self, layer: u8, row: usize, col: usize) -> Action {
+        let layer = &self.keymap[layer as usize];
+        match row.checked_sub(ROWS) {
+            None => layer.keys[row][col],
+            Some(encoder) => layer
+                .encoders
+                .get(encoder)
+                .map_or(Action::Transparent, |steps| steps[col]),
+        }
+    }
+
+    // Highest active layer with an action at a position
+    fn layer_at(&self, row: usize, col: usize) -> u8 {
+        let mut active = self.toggled | 1 << self.default;
+        for (layer, &held) in self.momentary.iter().enumerate() {
+            if held > 0 {
+                active |= 1 << layer;
+            }
+        }
+        if let Some(layer) = self.one_shot {
+            active |= 1 << layer;
+        }
+
+        (0..self.keymap.len() as u8)
+            .rev()
+            .filter(|&layer| active & 1 << layer != 0)
+            .find(|&layer| !matches!(self.action(layer, row, col), Action::Transparent))
+            .unwrap_or(self.default)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::KeyCode;
+    use crate::keypad::Sequence;
+
+    const A: Action = Action::Macro(Sequence::Presses(&[]));
+    const B: Action = Action::Macro(Sequence::Steps(&[]));
+    const T: Action = Action::Transparent;
+    const N: Action = Action::None;
+    const M: Action = Action::Momentary(1);
+
+    // Layer keys on the first row, a key and another momentary key on the second one.
+    // The second layer is a prefix layer.
+    const KEYMAP: &[Layer<2, 4>] = &[
+        Layer {
+            prefix: None,
+            keys: [
+                [
+                    Action::Momentary(1),
+                    Action::Toggle(1),
+                    Action::OneShot(1),
+                    Action::Default(1),
+                ],
+                [A, N, N, M],
+            ],
+            encoders: &[[A, N]],
+        },
+        Layer {
+            prefix: Some(&[KeyCode::LEFTCTRL, KeyCode::B]),
+            keys: [
+                [
+                    Action::Momentary(1),
+                    Action::Toggle(1),
+                    Action::OneShot(1),
+                    Action::Default(0),
+                ],
+                [B, T, N, M],
+            ],
+            encoders: &[[T, B]],
+        },
+    ];
+
+    fn event(row: u8, col: u8, pressed: bool) -> KeyEvent {
+        KeyEvent {
+            row,
+            col,
+            pressed,
+            timestamp: 0,
+        }
+    }
+
+    fn is_b(action: Action) -> bool {
+        matches!(action, Action::Macro(Sequence::Steps(_)))
+    }
+
+    #[test]
+    fn momentary_layer_is_active_while_held() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(1, 0, false));
+        layers.event(&event(0, 0, false));
+        assert!(!is_b(layers.event(&event(1, 0, true))));
+    }
+
+    #[test]
+    fn momentary_layer_stays_active_while_another_key_holds_it() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        layers.event(&event(1, 3, true));
+        layers.event(&event(0, 0, false));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(1, 0, false));
+        layers.event(&event(1, 3, false));
+        assert!(!is_b(layers.event(&event(1, 0, true))));
+    }
+
+    #[test]
+    fn key_is_released_on_the_layer_it_was_pressed_on() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(0, 0, false));
+        assert!(is_b(layers.event(&event(1, 0, false))));
+    }
+
+    #[test]
+    fn toggle_and_default_layers_stay_active() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 1, true));
+        layers.event(&event(0, 1, false));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(1, 0, false));
+        layers.event(&event(0, 1, true));
+        layers.event(&event(0, 1, false));
+        assert!(!is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(1, 0, false));
+
+        layers.event(&event(0, 3, true));
+        layers.event(&event(0, 3, false));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+    }
+
+    #[test]
+    fn one_shot_layer_applies_to_the_next_press_only() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 2, true));
+        layers.event(&event(0, 2, false));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        layers.event(&event(1, 0, false));
+        assert!(!is_b(layers.event(&event(1, 0, true))));
+    }
+
+    #[test]
+    fn transparent_positions_fall_through() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        assert!(matches!(layers.event(&event(1, 1, true)), Action::None));
+        assert!(matches!(layers.event(&event(1, 4, true)), Action::None));
+    }
+
+    #[test]
+    fn encoder_steps_have_their_own_positions() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        assert!(is_b(layers.event(&event(1, 0, true))));
+        // Clockwise falls through to the first layer
+        assert!(!is_b(layers.event(&event(2, 0, true))));
+        layers.event(&event(2, 0, false));
+        assert!(is_b(layers.event(&event(2, 1, true))));
+        layers.event(&event(2, 1, false));
+        // The held key is still released on its layer
+        layers.event(&event(0, 0, false));
+        assert!(is_b(layers.event(&event(1, 0, false))));
+        assert!(matches!(layers.event(&event(3, 0, true)), Action::None));
+    }
+
+    #[test]
+    fn prefix_comes_from_the_layer_a_key_was_pressed_on() {
+        let mut layers = Layers::new(KEYMAP);
+        layers.event(&event(0, 0, true));
+        layers.event(&event(1, 0, true));
+        layers.event(&event(1, 1, true));
+        assert_eq!(
+            layers.prefix(1, 0),
+            Some(&[KeyCode::LEFTCTRL, KeyCode::B][..])
+        );
+        // Transparent, from the layer below
+        assert_eq!(layers.prefix(1, 1), None);
+        // Not held
+        assert_eq!(layers.prefix(1, 2), None);
+    }
+}
@@ -0,0 +1,293 @@
+// Host keyboard layouts.
+// HID keycodes are key positions named after a US keyboard, so the key
+// typing a character depends on the layout the host is set to.
+// Characters only reachable through dead keys (e.g. ^ and ` on DE) are not
+// typeable on that layout.
+use crate::keycode::KeyCode;
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Layout {
+    Us,
+    Uk,
+    // QWERTZ
+    De,
+    // AZERTY
+    Fr,
+}
+
+// The key typing a character, and the modifiers to hold with it
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Typed {
+    pub key: KeyCode,
+    pub shift: bool,
+    pub altgr: bool,
+}
+
+const fn plain(key: KeyCode) -> Option<Typed> {
+    Some(Typed {
+        key,
+        shift: false,
+        altgr: false,
+    })
+}
+
+const fn shift(key: KeyCode) -> Option<Typed> {
+    Some(Typed {
+        key,
+        shift: true,
+        altgr: false,
+    })
+}
+
+const fn altgr(key: KeyCode) -> Option<Typed> {
+    Some(Typed {
+        key,
+        shift: false,
+        altgr: true,
+    })
+}
+
+// How to type an ASCII character on a layout
+pub const fn typed(layout: Layout, c: u8) -> Option<Typed> {
+    match c {
+        b'\n' => return plain(KeyCode::ENTER),
+        b'\t' => return plain(KeyCode::TAB),
+        b' ' => return plain(KeyCode::SPACE),
+        _ => {}
+    }
+
+    match layout {
+        Layout::Us => us(c),
+        Layout::Uk => uk(c),
+        Layout::De => de(c),
+        Layout::Fr => fr(c),
+    }
+}
+
+// The key of a letter on a QWERTY keyboard
+const fn letter(c: u8) -> KeyCode {
+    match c.to_ascii_lowercase() {
+        b'a' => KeyCode::A,
+        b'b' => KeyCode::B,
+        b'c' => KeyCode::C,
+        b'd' => KeyCode::D,
+        b'e' => KeyCode::E,
+        b'f' => KeyCode::F,
+        b'g' => KeyCode::G,
+        b'h' => KeyCode::H,
+        b'i' => KeyCode::I,
+        b'j' => KeyCode::J,
+        b'k' => KeyCode::K,
+        b'l' => KeyCode::L,
+        b'm' => KeyCode::M,
+        b'n' => KeyCode::N,
+        b'o' => KeyCode::O,
+        b'p' => KeyCode::P,
+        b'q' => KeyCode::Q,
+        b'r' => KeyCode::R,
+        b's' => KeyCode::S,
+        b't' => KeyCode::T,
+        b'u' => KeyCode::U,
+        b'v' => KeyCode::V,
+        b'w' => KeyCode::W,
+        b'x' => KeyCode::X,
+        b'y' => KeyCode::Y,
+        _ => KeyCode::Z,
+    }
+}
+
+// Letters are shifted when uppercase, whatever key they are on
+const fn letter_on(c: u8, key: KeyCode) -> Option<Typed> {
+    if c.is_ascii_uppercase() {
+        shift(key)
+    } else {
+        plain(key)
+    }
+}
+
+// The digit keys of the number row, from 1 to 0
+const DIGITS: [KeyCode; 10] = [
+    KeyCode::NUM1,
+    KeyCode::NUM2,
+    KeyCode::NUM3,
+    KeyCode::NUM4,
+    KeyCode::NUM5,
+    KeyCode::NUM6,
+    KeyCode::NUM7,
+    KeyCode::NUM8,
+    KeyCode::NUM9,
+    KeyCode::NUM0,
+];
+
+const fn digit(c: u8) -> KeyCode {
+    DIGITS[((c - b'0') as usize + 9) % 10]
+}
+
+const fn us(c: u8) -> Option<Typed> {
+    match c {
+        b'a'..=b'z' | b'A'..=b'Z' => letter_on(c, letter(c)),
+        b'0'..=b'9' => plain(digit(c)),
+        b'!' => shift(KeyCode::NUM1),
+        b'@' => shift(KeyCode::NUM2),
+        b'#' => shift(KeyCode::NUM3),
+        b'$' => shift(KeyCode::NUM4),
+        b'%' => shift(KeyCode::NUM5),
+        b'^' => shift(KeyCode::NUM6),
+        b'&' => shift(KeyCode::NUM7),
+        b'*' => shift(KeyCode::NUM8),
+        b'(' => shift(KeyCode::NUM9),
+        b')' => shift(KeyCode::NUM0),
+        b'-' => plain(KeyCode::MINUS),
+        b'_' => shift(KeyCode::MINUS),
+        b'=' => plain(KeyCode::EQUAL),
+        b'+' => shift(KeyCode::EQUAL),
+        b'[' => plain(KeyCode::LEFTBRACE),
+        b'{' => shift(KeyCode::LEFTBRACE),
+        b']' => plain(KeyCode::RIGHTBRACE),
+        b'}' => shift(KeyCode::RIGHTBRACE),
+        b'\\' => plain(KeyCode::BACKSLASH),
+        b'|' => shift(KeyCode::BACKSLASH),
+        b';' => plain(KeyCode::SEMICOLON),
+        b':' => shift(KeyCode::SEMICOLON),
+        b'\'' => plain(KeyCode::APOSTROPHE),
+        b'"' => shift(KeyCode::APOSTROPHE),
+        b'`' => plain(KeyCode::GRAVE),
+        b'~' => shift(KeyCode::GRAVE),
+        b',' => plain(KeyCode::COMMA),
+        b'<' => shift(KeyCode::COMMA),
+        b'.' => plain(KeyCode::DOT),
+        b'>' => shift(KeyCode::DOT),
+        b'/' => plain(KeyCode::SLASH),
+        b'?' => shift(KeyCode::SLASH),
+        _ => None,
+    }
+}
+
+// Same as US but for the keys around Enter and the extra ISO key
+const fn uk(c: u8) -> Option<Typed> {
+    match c {
+        b'"' => shift(KeyCode::NUM2),
+        b'@' => shift(KeyCode::APOSTROPHE),
+        b'#' => plain(KeyCode::HASHTILDE),
+        b'~' => shift(KeyCode::HASHTILDE),
+        b'\\' => plain(KeyCode::KEY102ND),
+        b'|' => shift(KeyCode::KEY102ND),
+        _ => us(c),
+    }
+}
+
+const fn de(c: u8) -> Option<Typed> {
+    match c {
+        b'y' | b'Y' => letter_on(c, KeyCode::Z),
+        b'z' | b'Z' => letter_on(c, KeyCode::Y),
+        b'a'..=b'x' | b'A'..=b'X' => letter_on(c, letter(c)),
+        b'0'..=b'9' => plain(digit(c)),
+        b'!' => shift(KeyCode::NUM1),
+        b'"' => shift(KeyCode::NUM2),
+        b'$' => shift(KeyCode::NUM4),
+        b'%' => shift(KeyCode::NUM5),
+        b'&' => shift(KeyCode::NUM6),
+        b'/' => shift(KeyCode::NUM7),
+        b'(' => shift(KeyCode::NUM8),
+        b')' => shift(KeyCode::NUM9),
+        b'=' => shift(KeyCode::NUM0),
+        b'{' => altgr(KeyCode::NUM7),
+        b'[' => altgr(KeyCode::NUM8),
+        b']' => altgr(KeyCode::NUM9),
+        b'}' => altgr(KeyCode::NUM0),
+        b'?' => shift(KeyCode::MINUS),
+        b'\\' => altgr(KeyCode::MINUS),
+        b'@' => altgr(KeyCode::Q),
+        b'+' => plain(KeyCode::RIGHTBRACE),
+        b'*' => shift(KeyCode::RIGHTBRACE),
+        b'~' => altgr(KeyCode::RIGHTBRACE),
+        b'#' => plain(KeyCode::HASHTILDE),
+        b'\'' => shift(KeyCode::HASHTILDE),
+        b',' => plain(KeyCode::COMMA),
+        b';' => shift(KeyCode::COMMA),
+        b'.' => plain(KeyCode::DOT),
+        b':' => shift(KeyCode::DOT),
+        b'-' => plain(KeyCode::SLASH),
+        b'_' => shift(KeyCode::SLASH),
+        b'<' => plain(KeyCode::KEY102ND),
+        b'>' => shift(KeyCode::KEY102ND),
+        b'|' => altgr(KeyCode::KEY102ND),
+        _ => None,
+    }
+}
+
+const fn fr(c: u8) -> Option<Typed> {
+    match c {
+        b'a' | b'A' => letter_on(c, KeyCode::Q),
+        b'q' | b'Q' => letter_on(c, KeyCode::A),
+        b'z' | b'Z' => letter_on(c, KeyCode::W),
+        b'w' | b'W' => letter_on(c, KeyCode::Z),
+        b'm' | b'M' => letter_on(c, KeyCode::SEMICOLON),
+        b'a'..=b'z' | b'A'..=b'Z' => letter_on(c, letter(c)),
+        b'0'..=b'9' => shift(digit(c)),
+        b'&' => plain(KeyCode::NUM1),
+        b'"' => plain(KeyCode::NUM3),
+        b'#' => altgr(KeyCode::NUM3),
+        b'\'' => plain(KeyCode::NUM4),
+        b'{' => altgr(KeyCode::NUM4),
+        b'(' => plain(KeyCode::NUM5),
+        b'[' => altgr(KeyCode::NUM5),
+        b'-' => plain(KeyCode::NUM6),
+        b'|' => altgr(KeyCode::NUM6),
+        b'_' => plain(KeyCode::NUM8),
+        b'\\' => altgr(KeyCode::NUM8),
+        b'^' => altgr(KeyCode::NUM9),
+        b'@' => altgr(KeyCode::NUM0),
+        b')' => plain(KeyCode::MINUS),
+        b']' => altgr(KeyCode::MINUS),
+        b'=' => plain(KeyCode::EQUAL),
+        b'+' => shift(KeyCode::EQUAL),
+        b'}' => altgr(KeyCode::EQUAL),
+        b'$' => plain(KeyCode::RIGHTBRACE),
+        b'%' => shift(KeyCode::APOSTROPHE),
+        b'*' => plain(KeyCode::HASHTILDE),
+        b',' => plain(KeyCode::M),
+        b'?' => shift(KeyCode::M),
+        b';' => plain(KeyCode::COMMA),
+        b'.' => shift(KeyCode::COMMA),
+        b':' => plain(KeyCode::DOT),
+        b'/' => shift(KeyCode::DOT),
+        b'!' => plain(KeyCode::SLASH),
+        b'<' => plain(KeyCode::KEY102ND),
+        b'>' => shift(KeyCode::KEY102ND),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn letters_follow_the_layout() {
+        assert_eq!(typed(Layout::Us, b'z').unwrap().key, KeyCode::Z);
+        assert_eq!(typed(Layout::De, b'z').unwrap().key, KeyCode::Y);
+        assert_eq!(typed(Layout::Fr, b'a').unwrap().key, KeyCode::Q);
+        assert_eq!(typed(Layout::Fr, b'M'), shift(KeyCode::SEMICOLON));
+    }
+
+    #[test]
+    fn symbols_use_shift_and_altgr() {
+        assert_eq!(typed(Layout::Uk, b'@'), shift(KeyCode::APOSTROPHE));
+        assert_eq!(typed(Layout::De, b'@'), altgr(KeyCode::Q));
+        assert_eq!(typed(Layout::De, b'|'), altgr(KeyCode::KEY102ND));
+        assert_eq!(typed(Layout::Fr, b'1'), shift(KeyCode::NUM1));
+        assert_eq!(typed(Layout::Fr, b'\\'), altgr(KeyCode::NUM8));
+    }
+
+    #[test]
+    fn every_printable_character_is_typeable_on_us() {
+        for c in b' '..=b'~' {
+            assert!(typed(Layout::Us, c).is_some());
+        }
+        // Dead keys on DE
+        assert!(typed(Layout::De, b'^').is_none());
+        assert!(typed(Layout::De, b'`').is_none());
+    }
+}
@@ -0,0 +1,145 @@
+// Leader key: after it, a short sequence of pad keys selects a macro, the way
+// a prefix key does in tmux or vim. Keys entered are looked up in a table of
+// sequences; the sequence ends on a match that no longer sequence starts with,
+// on a key that matches nothing, or when no key comes within the timeout.
+use heapless::Vec;
+
+use crate::keypad::{Key, LeaderSequence, Sequence};
+
+// Keys of the longest sequence
+pub const MAX_LEADER_KEYS: usize = 5;
+
+pub struct Leader {
+    sequences: &'static [LeaderSequence],
+    timeout_us: u64,
+    // Keys entered so far, while a sequence is entered
+    keys: Option<Vec<Key, MAX_LEADER_KEYS>>,
+    // Time of the leader key or of the last key entered
+    last: u64,
+}
+
+impl Leader {
+    pub fn new(sequences: &'static [LeaderSequence], timeout_us: u64) -> Self {
+        Self {
+            sequences,
+            timeout_us,
+            keys: None,
+            last: 0,
+        }
+    }
+
+    pub fn is_active(&self) -> bool {
+        self.keys.is_some()
+    }
+
+    // Start a new sequence, dropping the one being entered
+    pub fn start(&mut self, now: u64) {
+        self.keys = Some(Vec::new());
+        self.last = now;
+    }
+
+    // Add a key to the sequence being entered.
+    // Returns the macro of the sequence once it ends on a match.
+    pub fn key(&mut self, key: Key, now: u64) -> Option<Sequence> {
+        let keys = self.keys.as_mut()?;
+        if keys.push(key).is_err() {
+            self.keys = None;
+            return None;
+        }
+        self.last = now;
+
+        let mut longer = false;
+        let mut found = None;
+        for sequence in self.sequences.iter() {
+            if sequence.keys == keys.as_slice() {
+                found = Some(sequence.sequence);
+            } else if sequence.keys.starts_with(keys) {
+                longer = true;
+            }
+        }
+
+        if !longer {
+            self.keys = None;
+        }
+        found.filter(|_| !longer)
+    }
+
+    // End the sequence once no key came within the timeout.
+    // Returns its macro if the keys entered match one.
+    pub fn poll(&mut self, now: u64) -> Option<Sequence> {
+        let keys = self.keys.as_ref()?;
+        if now < self.last + self.timeout_us {
+            return None;
+        }
+
+        let found = self
+            .sequences
+            .iter()
+            .find(|sequence| sequence.keys == keys.as_slice())
+            .map(|sequence| sequence.sequence);
+        self.keys = None;
+        found
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SEQUENCES: &[LeaderSequence] = &[
+        LeaderSequence {
+            keys: &[(0, 0)],
+            sequence: Sequence::Unicode('a'),
+        },
+        LeaderSequence {
+            keys: &[(0, 0), (0, 1)],
+            sequence: Sequence::Unicode('b'),
+        },
+        LeaderSequence {
+            keys: &[(1, 0), (1, 1)],
+            sequence: Sequence::Unicode('c'),
+        },
+    ];
+
+    fn unicode(sequence: Option<Sequence>) -> Option<char> {
+        match sequence {
+            Some(Sequence::Unicode(c)) => Some(c),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn sequence_ends_on_its_last_key() {
+        let mut leader = Leader::new(SEQUENCES, 100);
+        leader.start(0);
+        assert_eq!(unicode(leader.key((1, 0), 10)), None);
+        assert!(leader.is_active());
+        assert_eq!(unicode(leader.key((1, 1), 20)), Some('c'));
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn shorter_sequence_matches_on_timeout() {
+        let mut leader = Leader::new(SEQUENCES, 100);
+        leader.start(0);
+        // (0, 0) could still be the start of (0, 0), (0, 1)
+        assert_eq!(unicode(leader.key((0, 0), 10)), None);
+        assert_eq!(unicode(leader.poll(109)), None);
+        assert!(leader.is_active());
+        assert_eq!(unicode(leader.poll(110)), Some('a'));
+        assert!(!leader.is_active());
+    }
+
+    #[test]
+    fn unknown_keys_end_the_sequence() {
+        let mut leader = Leader::new(SEQUENCES, 100);
+        leader.start(0);
+        assert_eq!(unicode(leader.key((2, 0), 10)), None);
+        assert!(!leader.is_active());
+
+        // Nothing entered before the timeout
+        leader.start(200);
+        assert_eq!(unicode(leader.poll(300)), None);
+        assert!(!leader.is_active());
+    }
+}
@@ -1,24 +1,89 @@
-#![no_std]
-#![no_main]
-
+#![cfg_attr(not(test), no_std)]
+#![cfg_attr(not(test), no_main)]
+// The firmware logic is unit tested on the host, where the entry point is left out
+#![cfg_attr(test, allow(dead_code, unused_imports))]
+
+mod combo;
+mod debounce;
+mod encoder;
+mod event;
 mod keycode;
 mod keypad;
-
-use core::convert::Infallible;
+mod layers;
+mod layout;
+mod leader;
+mod player;
+mod recorder;
+mod scanner;
+mod tap_hold;
+mod text;
+mod unicode;
 
 use defmt::*;
 use defmt_rtt as _;
-use embedded_hal::digital::v2::{InputPin, OutputPin};
+use embedded_hal::digital::v2::OutputPin;
+#[cfg(feature = "expander-scanner")]
+use fugit::RateExtU32;
 use panic_probe as _;
+#[cfg(feature = "analog-scanner")]
+use rp2040_hal::adc::Adc;
+#[cfg(not(feature = "expander-scanner"))]
+use rp2040_hal::gpio::DynPin;
+#[cfg(feature = "pio-scanner")]
+use rp2040_hal::Clock;
+use rp2040_hal::{
+    clocks::init_clocks_and_plls, entry, gpio::Pins, pac, usb::UsbBus, Sio, Timer, Watchdog,
+};
+#[cfg(feature = "expander-scanner")]
 use rp2040_hal::{
-    clocks::init_clocks_and_plls, entry, gpio::Pins, pac, usb::UsbBus, Clock, Sio, Watchdog,
+    gpio::{
+        bank0::{Gpio4, Gpio5, Gpio6},
+        FunctionI2C, Pin, PullUpInput,
+    },
+    I2C,
 };
 use usb_device::class_prelude::UsbBusAllocator;
 use usb_device::device::{UsbDeviceBuilder, UsbVidPid};
 use usbd_hid::descriptor::SerializedDescriptor;
 use usbd_hid::{descriptor::KeyboardReport, hid_class::HIDClass};
 
-use crate::keycode::KeyCode;
+use crate::debounce::{DebounceAlgorithm, Debouncer};
+use crate::encoder::Encoder;
+use crate::event::{EventQueue, KeyEvent};
+use crate::keypad::{encoder_keys, Action, COMBOS, KEYMAP, LEADER_SEQUENCES};
+use crate::layers::Layers;
+use crate::layout::Layout;
+use crate::leader::Leader;
+use crate::player::Player;
+use crate::recorder::Recorder;
+#[cfg(feature = "analog-scanner")]
+use crate::scanner::analog::{AnalogConfig, AnalogKeys, Multiplexer};
+#[cfg(feature = "direct-scanner")]
+use crate::scanner::direct::{DirectPins, Pull};
+#[cfg(feature = "expander-scanner")]
+use crate::scanner::expander::{Chip, Expander, ExpanderMode};
+#[cfg(not(any(feature = "direct-scanner", feature = "analog-scanner")))]
+use crate::scanner::matrix::DiodeDirection;
+#[cfg(not(any(
+    feature = "pio-scanner",
+    feature = "direct-scanner",
+    feature = "expander-scanner",
+    feature = "analog-scanner"
+)))]
+use crate::scanner::matrix::Matrix;
+#[cfg(not(any(
+    feature = "direct-scanner",
+    feature = "expander-scanner",
+    feature = "analog-scanner"
+)))]
+use crate::scanner::matrix::Polarity;
+#[cfg(feature = "pio-scanner")]
+use crate::scanner::pio::PioMatrix;
+#[cfg(feature = "expander-scanner")]
+use crate::scanner::LoopScanner;
+use crate::scanner::ScanQueue;
+use crate::tap_hold::{Flavor, Resolved, TapHoldConfig, TapHoldKeys};
+use crate::unicode::UnicodeMode;
 
 // Place this boot block at the start of the program image
 // Needed for the ROM bootloader get our code up and running
@@ -29,7 +94,42 @@ static BOOT2: [u8; 256] = rp2040_boot2::BOOT_LOADER_GENERIC_03H;
 const CRYSTAL_FREQUENCY_HZ: u32 = 12_000_000u32;
 
 const USB_POLLING_RATE_MS: u8 = 10;
+#[cfg(not(any(feature = "direct-scanner", feature = "analog-scanner")))]
 const MATRIX_SCAN_US: u32 = 10;
+const MATRIX_SCAN_FREQUENCY_HZ: u32 = 1_000;
+
+// Each macro press is held for one USB poll, then released for one more
+const MACRO_HOLD_US: u64 = USB_POLLING_RATE_MS as u64 * 1_000;
+const MACRO_GAP_US: u64 = USB_POLLING_RATE_MS as u64 * 1_000;
+// Keyboard layout the host is set to, text macros are typed for it
+const HOST_LAYOUT: Layout = Layout::Us;
+// Input method used to type Unicode characters until a macro changes it
+const UNICODE_MODE: UnicodeMode = UnicodeMode::Linux;
+// Macros played at the same time, and macros waiting for a free slot
+const MACRO_SLOTS: usize = 4;
+const MACRO_QUEUE_SIZE: usize = 8;
+// Key events a runtime recording can hold
+const RECORDING_SIZE: usize = 128;
+// Tap-hold keys held for the tapping term are a hold,
+// the flavor can decide it earlier when other keys are used meanwhile.
+// Tap-dance keys end when they are not tapped again within their term,
+// and the keys of a combo are pressed within the combo term.
+const TAP_HOLD: TapHoldConfig = TapHoldConfig {
+    tapping_term_us: 200_000,
+    flavor: Flavor::PermissiveHold,
+    retro_tapping: false,
+    tap_dance_term_us: 200_000,
+    combo_term_us: 50_000,
+};
+// A leader sequence ends when no key is entered for this long
+const LEADER_TIMEOUT_US: u64 = 1_000_000;
+
+const DEBOUNCE_ALGORITHM: DebounceAlgorithm = DebounceAlgorithm::DeferredPerKey;
+const DEBOUNCE_US: u64 = 5_000;
+
+// Encoders wired to the board, and quadrature transitions per encoder step
+const ENCODERS: usize = 1;
+const ENCODER_RESOLUTION: i8 = 4;
 
 const USB_KBD_VID: u16 = 0x16c0;
 const USB_KBD_PID: u16 = 0x27db;
@@ -37,52 +137,62 @@ const USB_KBD_PID: u16 = 0x27db;
 const ROWS: usize = 4;
 const COLS: usize = 4;
 
-fn send_press(hid: &HIDClass<UsbBus>, key: KeyCode, delay: &mut cortex_m::delay::Delay) {
-    let mut report = KeyboardReport {
-        modifier: 0,
-        reserved: 0,
-        leds: 0,
-        keycodes: [0; 6],
-    };
-
-    report.keycodes[0] = key as u8;
-    hid.push_input(&report).unwrap();
-    delay.delay_ms(USB_POLLING_RATE_MS.into());
-
-    report.keycodes[0] = 0;
-    hid.push_input(&report).unwrap();
-    delay.delay_ms(USB_POLLING_RATE_MS.into());
-}
-
-fn scan_matrix(
-    rows: &[&dyn InputPin<Error = Infallible>],
-    cols: &mut [&mut dyn OutputPin<Error = Infallible>],
-    delay: &mut cortex_m::delay::Delay,
-) -> [[bool; ROWS]; COLS] {
-    let mut matrix: [[bool; ROWS]; COLS] = [[false; ROWS]; COLS];
-
-    for (c, col) in cols.iter_mut().enumerate() {
-        col.set_high().unwrap();
-        delay.delay_us(MATRIX_SCAN_US);
-
-        for (r, row) in rows.iter().enumerate() {
-            matrix[c][r] = row.is_high().unwrap();
-        }
-
-        col.set_low().unwrap();
-        delay.delay_us(MATRIX_SCAN_US);
-    }
-
-    matrix
-}
+// The columns are strobed high and the rows are pulled down
+#[cfg(not(any(
+    feature = "direct-scanner",
+    feature = "expander-scanner",
+    feature = "analog-scanner"
+)))]
+const DIODE_DIRECTION: DiodeDirection = DiodeDirection::Col2Row;
+#[cfg(not(any(
+    feature = "direct-scanner",
+    feature = "expander-scanner",
+    feature = "analog-scanner"
+)))]
+const POLARITY: Polarity = Polarity::ActiveHigh;
+
+// With the direct scanner, one switch to ground per GPIO
+#[cfg(feature = "direct-scanner")]
+const DIRECT_PINS: usize = 8;
+#[cfg(feature = "direct-scanner")]
+const DIRECT_PULL: Pull = Pull::Up;
+
+// With the expander scanner, the switches are behind an I2C port expander
+// on GPIO4 (SDA) and GPIO5 (SCL), with its interrupt output on GPIO6
+#[cfg(feature = "expander-scanner")]
+const EXPANDER_CHIP: Chip = Chip::Mcp23017;
+#[cfg(feature = "expander-scanner")]
+const EXPANDER_ADDRESS: u8 = 0x20;
+#[cfg(feature = "expander-scanner")]
+const EXPANDER_MODE: ExpanderMode = ExpanderMode::Matrix {
+    rows: &[8, 9, 10, 11],
+    cols: &[0, 1, 2, 3],
+    direction: DiodeDirection::Col2Row,
+};
+#[cfg(feature = "expander-scanner")]
+type ExpanderI2c = I2C<pac::I2C0, (Pin<Gpio4, FunctionI2C>, Pin<Gpio5, FunctionI2C>)>;
+#[cfg(feature = "expander-scanner")]
+type ExpanderInt = Pin<Gpio6, PullUpInput>;
+
+// With the analog scanner, Hall-effect sensors behind a 16 channel
+// multiplexer selected by GPIO0-3 and read on GPIO26
+#[cfg(feature = "analog-scanner")]
+const ANALOG_MUX_SELECT_PINS: usize = 4;
+#[cfg(feature = "analog-scanner")]
+const ANALOG_CONFIG: AnalogConfig = AnalogConfig {
+    full_travel: -1200,
+    actuation: 128,
+    release: 100,
+    rapid_trigger: None,
+};
 
+#[cfg(not(test))]
 #[entry]
 fn main() -> ! {
     info!("Start");
 
     // Acquire our RP2040 peripherals
     let mut pac = pac::Peripherals::take().unwrap();
-    let core = pac::CorePeripherals::take().unwrap();
     let mut watchdog = Watchdog::new(pac.WATCHDOG);
     let sio = Sio::new(pac.SIO);
 
@@ -99,7 +209,12 @@ fn main() -> ! {
     .ok()
     .unwrap();
 
-    let mut delay = cortex_m::delay::Delay::new(core.SYST, clocks.system_clock.freq().to_Hz());
+    // Free running microsecond counter used to time the scan and debounce the keys
+    let timer = Timer::new(pac.TIMER, &mut pac.RESETS);
+    let timer = cortex_m::singleton!(: Timer = timer).unwrap();
+    #[cfg(not(feature = "expander-scanner"))]
+    let scan_alarm = timer.alarm_0().unwrap();
+    let timer: &'static Timer = timer;
 
     let pins = Pins::new(
         pac.IO_BANK0,
@@ -108,20 +223,94 @@ fn main() -> ! {
         &mut pac.RESETS,
     );
 
-    let cols: &mut [&mut dyn OutputPin<Error = Infallible>] = &mut [
-        &mut pins.gpio12.into_push_pull_output(),
-        &mut pins.gpio13.into_push_pull_output(),
-        &mut pins.gpio16.into_push_pull_output(),
-        &mut pins.gpio17.into_push_pull_output(),
+    #[cfg(not(any(
+        feature = "direct-scanner",
+        feature = "expander-scanner",
+        feature = "analog-scanner"
+    )))]
+    let cols: [DynPin; COLS] = [
+        pins.gpio12.into(),
+        pins.gpio13.into(),
+        pins.gpio16.into(),
+        pins.gpio17.into(),
     ];
 
-    let rows: &[&dyn InputPin<Error = Infallible>] = &[
-        &pins.gpio18.into_pull_down_input(),
-        &pins.gpio19.into_pull_down_input(),
-        &pins.gpio20.into_pull_down_input(),
-        &pins.gpio21.into_pull_down_input(),
+    #[cfg(not(any(
+        feature = "direct-scanner",
+        feature = "expander-scanner",
+        feature = "analog-scanner"
+    )))]
+    let rows: [DynPin; ROWS] = [
+        pins.gpio18.into(),
+        pins.gpio19.into(),
+        pins.gpio20.into(),
+        pins.gpio21.into(),
     ];
 
+    #[cfg(not(any(
+        feature = "pio-scanner",
+        feature = "direct-scanner",
+        feature = "expander-scanner",
+        feature = "analog-scanner"
+    )))]
+    let scanner = Matrix::new(rows, cols, DIODE_DIRECTION, POLARITY, timer);
+    #[cfg(feature = "pio-scanner")]
+    let scanner = PioMatrix::new(
+        rows,
+        cols,
+        DIODE_DIRECTION,
+        POLARITY,
+        pac.PIO0,
+        clocks.system_clock.freq().to_Hz(),
+        &mut pac.RESETS,
+    );
+    #[cfg(feature = "direct-scanner")]
+    let direct_pins: [DynPin; DIRECT_PINS] = [
+        pins.gpio12.into(),
+        pins.gpio13.into(),
+        pins.gpio16.into(),
+        pins.gpio17.into(),
+        pins.gpio18.into(),
+        pins.gpio19.into(),
+        pins.gpio20.into(),
+        pins.gpio21.into(),
+    ];
+    #[cfg(feature = "direct-scanner")]
+    let scanner = DirectPins::new(direct_pins, DIRECT_PULL);
+    #[cfg(feature = "expander-scanner")]
+    let scanner = Expander::new(
+        I2C::i2c0(
+            pac.I2C0,
+            pins.gpio4.into_mode(),
+            pins.gpio5.into_mode(),
+            400.kHz(),
+            &mut pac.RESETS,
+            &clocks.system_clock,
+        ),
+        pins.gpio6.into_pull_up_input(),
+        EXPANDER_CHIP,
+        EXPANDER_ADDRESS,
+        EXPANDER_MODE,
+    )
+    .ok()
+    .unwrap();
+    #[cfg(feature = "analog-scanner")]
+    let analog_select: [DynPin; ANALOG_MUX_SELECT_PINS] = [
+        pins.gpio0.into(),
+        pins.gpio1.into(),
+        pins.gpio2.into(),
+        pins.gpio3.into(),
+    ];
+    #[cfg(feature = "analog-scanner")]
+    let scanner = AnalogKeys::new(
+        Multiplexer::new(
+            Adc::new(pac.ADC, &mut pac.RESETS),
+            pins.gpio26.into_floating_input(),
+            analog_select,
+        ),
+        ANALOG_CONFIG,
+    );
+
     // Bring up the RP2040 USB bus
     let usb = UsbBus::new(
         pac.USBCTRL_REGS,
@@ -148,29 +337,181 @@ fn main() -> ! {
         .build();
 
     let mut led = pins.gpio25.into_push_pull_output();
-    let mut state = false;
+
+    // A knob on the spare GPIO14 and GPIO15
+    let mut encoders: [Encoder; ENCODERS] = [Encoder::new(
+        pins.gpio14.into(),
+        pins.gpio15.into(),
+        ENCODER_RESOLUTION,
+        encoder_keys(0),
+    )];
+
+    // The matrix is scanned from the timer interrupt, scans reach this loop through a queue
+    let scan_queue = cortex_m::singleton!(: ScanQueue = ScanQueue::new()).unwrap();
+    let (scan_producer, mut scan_consumer) = scan_queue.split();
+    #[cfg(not(feature = "expander-scanner"))]
+    scanner::start(
+        scanner,
+        timer,
+        scan_alarm,
+        MATRIX_SCAN_FREQUENCY_HZ,
+        scan_producer,
+    );
+    // except for the I2C expander, scanned from this loop
+    #[cfg(feature = "expander-scanner")]
+    let mut scanner = LoopScanner::new(scanner, timer, MATRIX_SCAN_FREQUENCY_HZ, scan_producer);
+
+    // Last known state of every key, and the events built from it
+    let mut debouncer = Debouncer::new(DEBOUNCE_ALGORITHM, DEBOUNCE_US);
+    let mut key_states = [[false; COLS]; ROWS];
+    let mut events = EventQueue::new();
+
+    // Macros are played a step at a time between scans, the report is sent
+    // again on the next iteration if the endpoint was busy
+    let mut player: Player<MACRO_SLOTS, MACRO_QUEUE_SIZE> =
+        Player::new(MACRO_HOLD_US, MACRO_GAP_US);
+    let mut report_pending = false;
+    // Held keys changed and not sent yet: events wait until they are, or a
+    // press and its release handled in one pass would never reach the host
+    let mut keys_pending = false;
+
+    // Key events recorded on the pad, replayed into the event queue
+    let mut recorder: Recorder<RECORDING_SIZE> = Recorder::new();
+
+    // Key events resolved to actions through combos, tap-hold keys and the layers
+    let mut layers = Layers::new(KEYMAP);
+    let mut tap_hold = TapHoldKeys::new(TAP_HOLD, COMBOS);
+    let mut leader = Leader::new(LEADER_SEQUENCES, LEADER_TIMEOUT_US);
 
     loop {
         usb_device.poll(&mut [&mut usb_hid]);
 
-        let scanned_matrix = scan_matrix(rows, cols, &mut delay);
+        #[cfg(feature = "expander-scanner")]
+        scanner.poll();
+        while let Some(scan) = scan_consumer.dequeue() {
+            let debounced_matrix = debouncer.update(&scan.matrix, scan.timestamp);
+            event::queue_changes(
+                &mut key_states,
+                debounced_matrix,
+                scan.timestamp,
+                &mut events,
+            );
+        }
+
+        // Encoder steps are taps of their own key positions
+        for encoder in encoders.iter_mut() {
+            if let Some((row, col)) = encoder.poll() {
+                // The press goes in with its release or not at all,
+                // so that keys held by the step cannot stay held
+                if events.capacity() - events.len() < 2 {
+                    warn!("Event queue full, dropping encoder step");
+                    continue;
+                }
+                let timestamp = timer.get_counter().ticks();
+                for pressed in [true, false] {
+                    let _ = events.push_back(KeyEvent {
+                        row,
+                        col,
+                        pressed,
+                        timestamp,
+                    });
+                }
+            }
+        }
 
-        for (_, col) in scanned_matrix.iter().enumerate() {
-            for (_, row) in col.iter().enumerate() {
-                let previous_state = state;
-                state = *row;
+        // A replayed event is gone once polled, so it is polled only if it fits
+        if !events.is_full() {
+            if let Some(event) = recorder.poll(timer.get_counter().ticks()) {
+                let _ = events.push_back(event);
+            }
+        }
 
-                match (previous_state, *row) {
-                    (false, true) => {
-                        led.set_high().unwrap();
-                        send_press(&usb_hid, KeyCode::A, &mut delay);
+        let now = timer.get_counter().ticks();
+        while !keys_pending {
+            let Some(Resolved {
+                event,
+                action,
+                synthetic,
+                prefix,
+            }) = tap_hold.next(now, &mut events, &mut layers, leader.is_active())
+            else {
+                break;
+            };
+            debug!("{}", event);
+
+            // Presses while a leader sequence is entered only enter it.
+            // Synthetic presses are dropped, the pad keys behind them are entered.
+            let leading = event.pressed && leader.is_active();
+
+            match action {
+                _ if leading && synthetic => {}
+                _ if leading => {
+                    if !recorder.record(&event) {
+                        warn!("Recording full, dropping key event");
                     }
-                    (true, false) => {
-                        led.set_low().unwrap();
+                    let sequence = leader.key((event.row, event.col), event.timestamp);
+                    if sequence.is_some_and(|sequence| !player.play(sequence)) {
+                        warn!("Macro queue full, dropping macro");
                     }
-                    (_, _) => {}
                 }
+                Action::Record if event.pressed => {
+                    if recorder.is_recording() {
+                        info!("Recording stopped");
+                        recorder.stop(event.timestamp);
+                    } else {
+                        info!("Recording started");
+                        recorder.start(event.timestamp);
+                    }
+                }
+                Action::Play if event.pressed => recorder.play(event.timestamp),
+                Action::Record | Action::Play => {}
+                // Layer keys are recorded too, so replays resolve on the same layers.
+                // Synthetic events are not, the replayed events lead to them again.
+                action => {
+                    if !synthetic && !recorder.record(&event) {
+                        warn!("Recording full, dropping key event");
+                    }
+                    match action {
+                        Action::Macro(sequence)
+                            if event.pressed && !player.play_after(prefix, sequence) =>
+                        {
+                            warn!("Macro queue full, dropping macro");
+                        }
+                        Action::Keys(press) => {
+                            if event.pressed {
+                                player.hold(press);
+                            } else {
+                                player.release(press);
+                            }
+                            report_pending = true;
+                            keys_pending = true;
+                        }
+                        Action::Leader if event.pressed => leader.start(event.timestamp),
+                        _ => {}
+                    }
+                }
+            }
+        }
+
+        if let Some(sequence) = leader.poll(now) {
+            if !player.play(sequence) {
+                warn!("Macro queue full, dropping macro");
             }
         }
+
+        // The LED is lit while a key is held, or a leader sequence is entered
+        let lit = leader.is_active() || key_states.iter().flatten().any(|&pressed| pressed);
+        led.set_state(lit.into()).unwrap();
+
+        report_pending |= player.tick(timer.get_counter().ticks());
+        if report_pending {
+            report_pending = usb_hid.push_input(player.report()).is_err();
+            keys_pending &= report_pending;
+        }
+
+        // Sleep until the next scan. The expander is scanned by this loop
+        // with no interrupt to wake it up, so it keeps running instead.
+        #[cfg(not(feature = "expander-scanner"))]
+        cortex_m::asm::wfi();
     }
 }
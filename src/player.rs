@@ -0,0 +1,579 @@
+// Play macros without blocking the main loop.
+// Every tick moves each playing macro forward by at most one step, so scanning
+// and USB polling keep running while a long macro is sent.
+use heapless::{Deque, Vec};
+use usbd_hid::descriptor::KeyboardReport;
+
+use crate::keycode::KeyCode;
+use crate::keypad::{Press, Sequence, Step};
+use crate::unicode::{self, UnicodeMode};
+use crate::UNICODE_MODE;
+
+// Nested repeat and hold blocks a macro can be in at once,
+// deeper blocks are skipped
+const MAX_DEPTH: usize = 4;
+// Keys a macro can hold down at once: 6 keycodes and 8 modifiers, plus spares
+const MAX_HELD: usize = 16;
+
+// A block of steps being played
+struct Frame {
+    sequence: Sequence,
+    index: usize,
+    // Times the block is still to be played, including this one
+    repeats: u16,
+    // Keys to release once the block is done
+    release: Option<Press>,
+}
+
+// A macro being played, and where it is in its steps
+struct Playback {
+    frames: Vec<Frame, MAX_DEPTH>,
+    held: Vec<KeyCode, MAX_HELD>,
+    // Keys tapped before the first step, like the prefix key of tmux
+    prefix: Option<Press>,
+    // Played with a prefix: no other macro plays meanwhile, or its keys
+    // would be merged with the prefix
+    alone: bool,
+    // Keys of a tap, released on the next step
+    tap: Option<Press>,
+    // Time of the next step
+    next_at: u64,
+}
+
+// Plays up to SLOTS macros at once, their held keys are merged into one report.
+// Macros started while every slot is busy wait in a queue of QUEUE macros.
+// Macros with a prefix play alone, after the macros playing and before the next ones.
+pub struct Player<const SLOTS: usize, const QUEUE: usize> {
+    // How long a press is held, then how long to wait before the next one
+    hold_us: u64,
+    gap_us: u64,
+    slots: [Option<Playback>; SLOTS],
+    queue: Deque<(Option<Press>, Sequence), QUEUE>,
+    // Keys held down by pad keys, outside of any macro
+    keys: Vec<KeyCode, MAX_HELD>,
+    report: KeyboardReport,
+    // Input method used to type Unicode characters, macros can change it
+    unicode_mode: UnicodeMode,
+}
+
+impl<const SLOTS: usize, const QUEUE: usize> Player<SLOTS, QUEUE> {
+    pub fn new(hold_us: u64, gap_us: u64) -> Self {
+        Self {
+            hold_us,
+            gap_us,
+            slots: [(); SLOTS].map(|_| None),
+            queue: Deque::new(),
+            keys: Vec::new(),
+            report: empty_report(),
+            unicode_mode: UNICODE_MODE,
+        }
+    }
+
+    // Start a macro on the next tick, or queue it if every slot is busy.
+    // Returns false if the macro was dropped because the queue is full.
+    pub fn play(&mut self, sequence: impl Into<Sequence>) -> bool {
+        self.play_after(None, sequence)
+    }
+
+    // Same as play, tapping a prefix first if there is one
+    pub fn play_after(&mut self, prefix: Option<Press>, sequence: impl Into<Sequence>) -> bool {
+        let sequence = sequence.into();
+        if self.queue.is_empty() && self.can_start(prefix) {
+            self.start(prefix, sequence);
+            true
+        } else {
+            self.queue.push_back((prefix, sequence)).is_ok()
+        }
+    }
+
+    // Whether a macro can start now, in a free slot
+    fn can_start(&self, prefix: Option<Press>) -> bool {
+        let mut playing = self.slots.iter().flatten();
+        match prefix {
+            Some(_) => playing.next().is_none(),
+            None => {
+                playing.all(|playback| !playback.alone) && self.slots.iter().any(Option::is_none)
+            }
+        }
+    }
+
+    fn start(&mut self, prefix: Option<Press>, sequence: Sequence) {
+        if let Some(slot) = self.slots.iter_mut().find(|slot| slot.is_none()) {
+            *slot = Some(Playback::new(prefix, sequence));
+        }
+    }
+
+    // Move every playing macro forward and start queued macros in the free slots.
+    // Returns true when the keys held down changed and the report must be sent again.
+    pub fn tick(&mut self, now: u64) -> bool {
+        let mut changed = false;
+
+        for slot in self.slots.iter_mut() {
+            if let Some(playback) = slot {
+                if now < playback.next_at {
+                    continue;
+                }
+
+                match playback.advance(now, self.hold_us, self.gap_us, &mut self.unicode_mode) {
+                    Some(step_changed) => changed |= step_changed,
+                    None => *slot = None,
+                }
+            }
+        }
+
+        // Queued macros start in order, as long as they can
+        while let Some(&(prefix, sequence)) = self.queue.front() {
+            if !self.can_start(prefix) {
+                break;
+            }
+            self.queue.pop_front();
+            self.start(prefix, sequence);
+        }
+
+        if changed {
+            self.update_report();
+        }
+
+        changed
+    }
+
+    // Hold keys down until they are released, whatever macros play meanwhile.
+    // The report must be sent again after both.
+    pub fn hold(&mut self, press: Press) {
+        for &key in press {
+            if !self.keys.contains(&key) {
+                let _ = self.keys.push(key);
+            }
+        }
+        self.update_report();
+    }
+
+    pub fn release(&mut self, press: Press) {
+        self.keys.retain(|key| !press.contains(key));
+        self.update_report();
+    }
+
+    fn update_report(&mut self) {
+        self.report = empty_report();
+        add_press(&mut self.report, &self.keys);
+        for playback in self.slots.iter().flatten() {
+            add_press(&mut self.report, &playback.held);
+        }
+    }
+
+    // Keys currently held down by all the playing macros
+    pub fn report(&self) -> &KeyboardReport {
+        &self.report
+    }
+}
+
+impl Playback {
+    fn new(prefix: Option<Press>, sequence: Sequence) -> Self {
+        let mut frames = Vec::new();
+        let _ = frames.push(Frame {
+            sequence,
+            index: 0,
+            repeats: 1,
+            release: None,
+        });
+
+        Self {
+            frames,
+            held: Vec::new(),
+            prefix,
+            alone: prefix.is_some(),
+            tap: None,
+            next_at: 0,
+        }
+    }
+
+    // Play steps until one presses or releases keys, or waits.
+    // Returns whether the held keys changed, or None once the macro is done.
+    fn advance(
+        &mut self,
+        now: u64,
+        hold_us: u64,
+        gap_us: u64,
+        unicode_mode: &mut UnicodeMode,
+    ) -> Option<bool> {
+        if let Some(press) = self.tap.take() {
+            self.release(press);
+            self.next_at = now + gap_us;
+            return Some(true);
+        }
+        if let Some(press) = self.prefix.take() {
+            self.press(press);
+            self.tap = Some(press);
+            self.next_at = now + hold_us;
+            return Some(true);
+        }
+
+        loop {
+            let Some(frame) = self.frames.last_mut() else {
+                // Keys left down by the macro are released before it ends
+                if self.held.is_empty() {
+                    return None;
+                }
+                self.held.clear();
+                self.next_at = now + gap_us;
+                return Some(true);
+            };
+
+            let step = match frame.sequence {
+                Sequence::Presses(presses) => {
+                    presses.get(frame.index).map(|&press| Step::Tap(press))
+                }
+                Sequence::Steps(steps) => steps.get(frame.index).copied(),
+                Sequence::Text(text) => text.press(frame.index).map(Step::Tap),
+                Sequence::Unicode(c) => unicode::step(c, *unicode_mode, frame.index),
+            };
+            frame.index += 1;
+
+            match step {
+                None => {
+                    if frame.repeats > 1 {
+                        frame.repeats -= 1;
+                        frame.index = 0;
+                        continue;
+                    }
+                    if let Some(press) = self.frames.pop().and_then(|frame| frame.release) {
+                        self.release(press);
+                        self.next_at = now + gap_us;
+                        return Some(true);
+                    }
+                }
+                Some(Step::Down(press)) => {
+                    self.press(press);
+                    self.next_at = now + hold_us;
+                    return Some(true);
+                }
+                Some(Step::Up(press)) => {
+                    self.release(press);
+                    self.next_at = now + gap_us;
+                    return Some(true);
+                }
+                Some(Step::Tap(press)) => {
+                    self.press(press);
+                    self.tap = Some(press);
+                    self.next_at = now + hold_us;
+                    return Some(true);
+                }
+                Some(Step::Text(text)) => {
+                    let _ = self.frames.push(Frame {
+                        sequence: Sequence::Text(text),
+                        index: 0,
+                        repeats: 1,
+                        release: None,
+                    });
+                }
+                Some(Step::Unicode(c)) => {
+                    let _ = self.frames.push(Frame {
+                        sequence: Sequence::Unicode(c),
+                        index: 0,
+                        repeats: 1,
+                        release: None,
+                    });
+                }
+                Some(Step::UnicodeMode(mode)) => *unicode_mode = mode,
+                Some(Step::Wait(ms)) => {
+                    self.next_at = now + ms as u64 * 1_000;
+                    return Some(false);
+                }
+                Some(Step::Repeat(repeats, steps)) => {
+                    if repeats > 0 {
+                        let _ = self.frames.push(Frame {
+                            sequence: Sequence::Steps(steps),
+                            index: 0,
+                            repeats,
+                            release: None,
+                        });
+                    }
+                }
+                Some(Step::Hold(press, steps)) => {
+                    let frame = Frame {
+                        sequence: Sequence::Steps(steps),
+                        index: 0,
+                        repeats: 1,
+                        release: Some(press),
+                    };
+                    if self.frames.push(frame).is_ok() {
+                        self.press(press);
+                        self.next_at = now + hold_us;
+                        return Some(true);
+                    }
+                }
+            }
+        }
+    }
+
+    fn press(&mut self, press: Press) {
+        for &key in press {
+            if !self.held.contains(&key) {
+                let _ = self.held.push(key);
+            }
+        }
+    }
+
+    fn release(&mut self, press: Press) {
+        self.held.retain(|key| !press.contains(key));
+    }
+}
+
+fn empty_report() -> KeyboardReport {
+    KeyboardReport {
+        modifier: 0,
+        reserved: 0,
+        leds: 0,
+        keycodes: [0; 6],
+    }
+}
+
+// Add every keycode of a press to a report.
+// Keycodes already in the report are not repeated, and keycodes past
+// the 6 a boot keyboard report can hold are dropped.
+fn add_press(report: &mut KeyboardReport, press: &[KeyCode]) {
+    for &key in press {
+        match key.modifier_bit() {
+            Some(bit) => report.modifier |= bit,
+            None => {
+                let code = key as u8;
+                if report.keycodes.contains(&code) {
+                    continue;
+                }
+                if let Some(slot) = report.keycodes.iter_mut().find(|slot| **slot == 0) {
+                    *slot = code;
+                }
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keypad::{Macro, Steps};
+    use crate::text::Text;
+
+    const CTRL_B_N: Macro = &[&[KeyCode::LEFTCTRL, KeyCode::B], &[KeyCode::N]];
+    const X: Macro = &[&[KeyCode::X]];
+    const P: Macro = &[&[KeyCode::P]];
+    const ALT_TAB_TAB: Steps = &[Step::Hold(
+        &[KeyCode::LEFTALT],
+        &[Step::Repeat(2, &[Step::Tap(&[KeyCode::TAB])])],
+    )];
+    const SHIFT_WAIT: Steps = &[
+        Step::Down(&[KeyCode::LEFTSHIFT]),
+        Step::Wait(1),
+        Step::Up(&[KeyCode::LEFTSHIFT]),
+    ];
+
+    #[test]
+    fn press_sends_keycodes_and_modifiers_together() {
+        let mut report = empty_report();
+        add_press(
+            &mut report,
+            &[KeyCode::LEFTCTRL, KeyCode::B, KeyCode::RIGHTSHIFT],
+        );
+        assert_eq!(report.modifier, 0b0010_0001);
+        assert_eq!(report.keycodes, [KeyCode::B as u8, 0, 0, 0, 0, 0]);
+    }
+
+    #[test]
+    fn presses_are_held_then_released_in_order() {
+        let mut player: Player<2, 2> = Player::new(10, 5);
+        player.play(CTRL_B_N);
+
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b1);
+        assert_eq!(player.report().keycodes[0], KeyCode::B as u8);
+
+        assert!(!player.tick(9));
+        assert!(player.tick(10));
+        assert_eq!(player.report().modifier, 0);
+        assert_eq!(player.report().keycodes, [0; 6]);
+
+        assert!(!player.tick(14));
+        assert!(player.tick(15));
+        assert_eq!(player.report().keycodes[0], KeyCode::N as u8);
+
+        assert!(player.tick(25));
+        // Nothing left to press once the last press is released
+        assert!(!player.tick(30));
+        assert!(player.slots[0].is_none());
+    }
+
+    #[test]
+    fn held_keys_stay_down_across_steps() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.play(ALT_TAB_TAB);
+
+        let tab = KeyCode::TAB as u8;
+        let mut reports = [(0, 0); 6];
+        let mut count = 0;
+        for now in 0..100 {
+            if player.tick(now) {
+                reports[count] = (player.report().modifier, player.report().keycodes[0]);
+                count += 1;
+            }
+        }
+
+        assert_eq!(count, 6);
+        assert_eq!(
+            reports,
+            [
+                (0b100, 0),
+                (0b100, tab),
+                (0b100, 0),
+                (0b100, tab),
+                (0b100, 0),
+                (0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn text_is_typed_one_tap_per_character() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.play(Sequence::Text(Text::new("Hi")));
+
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b10);
+        assert_eq!(player.report().keycodes[0], KeyCode::H as u8);
+        assert!(player.tick(10));
+        assert!(player.tick(15));
+        assert_eq!(player.report().modifier, 0);
+        assert_eq!(player.report().keycodes[0], KeyCode::I as u8);
+    }
+
+    #[test]
+    fn unicode_mode_can_be_changed_by_a_macro() {
+        const LAMBDA_ON_MAC: Steps = &[Step::UnicodeMode(UnicodeMode::MacOs), Step::Unicode('λ')];
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.play(LAMBDA_ON_MAC);
+
+        // Option is held while the digits are typed
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b100);
+        assert!(player.tick(10));
+        assert_eq!(player.report().keycodes[0], KeyCode::NUM0 as u8);
+        assert_eq!(player.unicode_mode, UnicodeMode::MacOs);
+    }
+
+    #[test]
+    fn waits_delay_the_next_step() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.play(SHIFT_WAIT);
+
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b10);
+        // Held for 10us, then the wait of 1ms
+        assert!(!player.tick(10));
+        assert!(!player.tick(1_009));
+        assert!(player.tick(1_010));
+        assert_eq!(player.report().modifier, 0);
+    }
+
+    #[test]
+    fn macros_in_different_slots_are_merged() {
+        let mut player: Player<2, 2> = Player::new(10, 5);
+        player.play(CTRL_B_N);
+        player.play(X);
+
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b1);
+        assert_eq!(
+            player.report().keycodes,
+            [KeyCode::B as u8, KeyCode::X as u8, 0, 0, 0, 0]
+        );
+    }
+
+    #[test]
+    fn held_keys_are_merged_with_macros() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.hold(&[KeyCode::LEFTCTRL]);
+        assert_eq!(player.report().modifier, 0b1);
+
+        player.play(X);
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b1);
+        assert_eq!(player.report().keycodes[0], KeyCode::X as u8);
+
+        player.release(&[KeyCode::LEFTCTRL]);
+        assert_eq!(player.report().modifier, 0);
+        assert_eq!(player.report().keycodes[0], KeyCode::X as u8);
+    }
+
+    #[test]
+    fn prefix_is_tapped_before_the_macro() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        player.play_after(Some(&[KeyCode::LEFTCTRL, KeyCode::B]), X);
+
+        assert!(player.tick(0));
+        assert_eq!(player.report().modifier, 0b1);
+        assert_eq!(player.report().keycodes[0], KeyCode::B as u8);
+        assert!(player.tick(10));
+        assert_eq!(player.report().keycodes[0], 0);
+        assert!(player.tick(15));
+        assert_eq!(player.report().modifier, 0);
+        assert_eq!(player.report().keycodes[0], KeyCode::X as u8);
+    }
+
+    #[test]
+    fn macros_with_a_prefix_play_one_after_another() {
+        let mut player: Player<4, 4> = Player::new(10, 5);
+        let ctrl_b: Press = &[KeyCode::LEFTCTRL, KeyCode::B];
+        player.play_after(Some(ctrl_b), P);
+        player.play_after(Some(ctrl_b), X);
+        // Waits for the second one, whatever the free slots
+        player.play(CTRL_B_N);
+
+        let mut reports = [(0, 0); 12];
+        let mut count = 0;
+        for now in 0..200 {
+            if player.tick(now) {
+                reports[count] = (player.report().modifier, player.report().keycodes[0]);
+                count += 1;
+            }
+        }
+
+        let (b, p, x, n) = (
+            KeyCode::B as u8,
+            KeyCode::P as u8,
+            KeyCode::X as u8,
+            KeyCode::N as u8,
+        );
+        assert_eq!(count, 12);
+        assert_eq!(
+            reports,
+            [
+                (1, b),
+                (0, 0),
+                (0, p),
+                (0, 0),
+                (1, b),
+                (0, 0),
+                (0, x),
+                (0, 0),
+                (1, b),
+                (0, 0),
+                (0, n),
+                (0, 0)
+            ]
+        );
+    }
+
+    #[test]
+    fn macros_wait_in_the_queue_for_a_free_slot() {
+        let mut player: Player<1, 1> = Player::new(10, 5);
+        assert!(player.play(X));
+        assert!(player.play(CTRL_B_N));
+        assert!(!player.play(X));
+
+        player.tick(0);
+        player.tick(10);
+        // X is done, the queued macro takes its slot
+        player.tick(15);
+        assert!(player.tick(16));
+        assert_eq!(player.report().keycodes[0], KeyCode::B as u8);
+    }
+}
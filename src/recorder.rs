@@ -0,0 +1,312 @@
+// Record key events on the pad and replay them later with the same timing.
+// Replayed events go through the main loop like scanned ones, so they play
+// the macros of the keys that were recorded.
+use heapless::Vec;
+
+use crate::event::KeyEvent;
+use crate::keypad::Key;
+
+// A recorded key event, and the time since the previous one
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Recorded {
+    row: u8,
+    col: u8,
+    pressed: bool,
+    delay_us: u32,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    Idle,
+    // Time of the last recorded event
+    Recording { last: u64 },
+    // Next event to replay, and when
+    Replaying { index: usize, next_at: u64 },
+}
+
+// Records up to N key events, events past N are dropped.
+// Every recorded press has its release recorded too, so a replay cannot leave
+// a key held: room is kept for the releases of the keys held, and the keys
+// still held when the recording stops are released. A replay stopped by
+// another one or by a recording releases the keys it holds first.
+pub struct Recorder<const N: usize> {
+    events: Vec<Recorded, N>,
+    // Keys whose press was recorded, but not their release yet
+    held: Vec<Key, N>,
+    // Keys whose press was replayed, but not their release yet
+    replayed: Vec<Key, N>,
+    // Keys of a stopped replay still to be released
+    releases: Vec<Key, N>,
+    state: State,
+}
+
+impl<const N: usize> Recorder<N> {
+    pub fn new() -> Self {
+        Self {
+            events: Vec::new(),
+            held: Vec::new(),
+            replayed: Vec::new(),
+            releases: Vec::new(),
+            state: State::Idle,
+        }
+    }
+
+    pub fn is_recording(&self) -> bool {
+        matches!(self.state, State::Recording { .. })
+    }
+
+    // Forget the previous recording and record from now on.
+    // Stops a replay in progress.
+    pub fn start(&mut self, now: u64) {
+        self.stop_replay();
+        self.events.clear();
+        self.held.clear();
+        self.state = State::Recording { last: now };
+    }
+
+    // Stop recording, releasing the keys still held
+    pub fn stop(&mut self, now: u64) {
+        let State::Recording { mut last } = self.state else {
+            return;
+        };
+        for (row, col) in core::mem::take(&mut self.held) {
+            self.push(row, col, false, now, &mut last);
+        }
+        self.state = State::Idle;
+    }
+
+    // Record an event while recording.
+    // Returns false if the event was dropped because the buffer is full.
+    pub fn record(&mut self, event: &KeyEvent) -> bool {
+        let State::Recording { mut last } = self.state else {
+            return true;
+        };
+
+        let key = (event.row, event.col);
+        if event.pressed {
+            // The press, and the releases of every held key including this one
+            if self.events.len() + self.held.len() + 2 > N {
+                return false;
+            }
+            let _ = self.held.push(key);
+        } else {
+            // Releases of keys whose press was not recorded would do nothing
+            let Some(i) = self.held.iter().position(|&held| held == key) else {
+                return true;
+            };
+            self.held.swap_remove(i);
+        }
+
+        self.push(
+            event.row,
+            event.col,
+            event.pressed,
+            event.timestamp,
+            &mut last,
+        );
+        self.state = State::Recording { last };
+        true
+    }
+
+    // Room was kept for it, the push cannot fail
+    fn push(&mut self, row: u8, col: u8, pressed: bool, timestamp: u64, last: &mut u64) {
+        let _ = self.events.push(Recorded {
+            row,
+            col,
+            pressed,
+            delay_us: timestamp.saturating_sub(*last).min(u32::MAX as u64) as u32,
+        });
+        *last = timestamp;
+    }
+
+    // Replay the recording from the start, unless recording.
+    // Restarts a replay in progress.
+    pub fn play(&mut self, now: u64) {
+        if !self.is_recording() {
+            self.stop_replay();
+            self.state = State::Replaying {
+                index: 0,
+                next_at: now,
+            };
+        }
+    }
+
+    // Queue the releases of the keys held by a replay in progress
+    fn stop_replay(&mut self) {
+        if let State::Replaying { .. } = self.state {
+            for key in core::mem::take(&mut self.replayed) {
+                let _ = self.releases.push(key);
+            }
+            self.state = State::Idle;
+        }
+    }
+
+    // The next replayed event once it is due,
+    // after the releases of a stopped replay
+    pub fn poll(&mut self, now: u64) -> Option<KeyEvent> {
+        if let Some((row, col)) = self.releases.pop() {
+            return Some(KeyEvent {
+                row,
+                col,
+                pressed: false,
+                timestamp: now,
+            });
+        }
+
+        let State::Replaying { index, next_at } = &mut self.state else {
+            return None;
+        };
+
+        let Some(recorded) = self.events.get(*index) else {
+            self.state = State::Idle;
+            return None;
+        };
+
+        let due = *next_at + recorded.delay_us as u64;
+        if now < due {
+            return None;
+        }
+
+        *index += 1;
+        *next_at = due;
+        let key = (recorded.row, recorded.col);
+        if recorded.pressed {
+            let _ = self.replayed.push(key);
+        } else if let Some(i) = self.replayed.iter().position(|&held| held == key) {
+            self.replayed.swap_remove(i);
+        }
+        Some(KeyEvent {
+            row: recorded.row,
+            col: recorded.col,
+            pressed: recorded.pressed,
+            timestamp: now,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(col: u8, pressed: bool, timestamp: u64) -> KeyEvent {
+        KeyEvent {
+            row: 0,
+            col,
+            pressed,
+            timestamp,
+        }
+    }
+
+    #[test]
+    fn events_are_replayed_with_their_timing() {
+        let mut recorder: Recorder<8> = Recorder::new();
+        recorder.start(1_000);
+        recorder.record(&event(0, true, 1_500));
+        recorder.record(&event(0, false, 1_600));
+        recorder.record(&event(1, true, 2_600));
+        recorder.record(&event(1, false, 2_700));
+        recorder.stop(3_000);
+
+        recorder.play(10_000);
+        assert_eq!(recorder.poll(10_499), None);
+        assert_eq!(recorder.poll(10_500), Some(event(0, true, 10_500)));
+        assert_eq!(recorder.poll(10_550), None);
+        assert_eq!(recorder.poll(10_600), Some(event(0, false, 10_600)));
+        assert_eq!(recorder.poll(11_600), Some(event(1, true, 11_600)));
+        assert_eq!(recorder.poll(11_700), Some(event(1, false, 11_700)));
+        assert_eq!(recorder.poll(20_000), None);
+    }
+
+    #[test]
+    fn keys_held_when_the_recording_stops_are_released() {
+        let mut recorder: Recorder<8> = Recorder::new();
+        recorder.start(0);
+        recorder.record(&event(0, true, 100));
+        // Pressed before the recording started
+        recorder.record(&event(1, false, 200));
+        recorder.stop(1_000);
+
+        recorder.play(0);
+        assert_eq!(recorder.poll(100), Some(event(0, true, 100)));
+        assert_eq!(recorder.poll(999), None);
+        assert_eq!(recorder.poll(1_000), Some(event(0, false, 1_000)));
+        assert_eq!(recorder.poll(2_000), None);
+    }
+
+    #[test]
+    fn room_is_kept_for_the_releases_of_held_keys() {
+        let mut recorder: Recorder<4> = Recorder::new();
+        recorder.start(0);
+        assert!(recorder.record(&event(0, true, 1)));
+        assert!(recorder.record(&event(1, true, 2)));
+        assert!(!recorder.record(&event(2, true, 3)));
+        assert!(recorder.record(&event(2, false, 4)));
+        assert!(recorder.record(&event(0, false, 5)));
+        recorder.stop(6);
+
+        recorder.play(0);
+        let replayed: [_; 4] = core::array::from_fn(|_| recorder.poll(10).unwrap());
+        assert_eq!(
+            replayed.map(|event| (event.col, event.pressed)),
+            [(0, true), (1, true), (0, false), (1, false)]
+        );
+        assert_eq!(recorder.poll(10), None);
+    }
+
+    #[test]
+    fn replaying_again_releases_the_keys_of_the_replay() {
+        let mut recorder: Recorder<4> = Recorder::new();
+        recorder.start(0);
+        recorder.record(&event(0, true, 100));
+        recorder.record(&event(0, false, 200));
+        recorder.stop(300);
+
+        recorder.play(1_000);
+        assert_eq!(recorder.poll(1_100), Some(event(0, true, 1_100)));
+        recorder.play(1_150);
+        assert_eq!(recorder.poll(1_150), Some(event(0, false, 1_150)));
+        assert_eq!(recorder.poll(1_249), None);
+        assert_eq!(recorder.poll(1_250), Some(event(0, true, 1_250)));
+        assert_eq!(recorder.poll(1_350), Some(event(0, false, 1_350)));
+        assert_eq!(recorder.poll(2_000), None);
+    }
+
+    #[test]
+    fn recording_during_a_replay_releases_its_keys() {
+        let mut recorder: Recorder<4> = Recorder::new();
+        recorder.start(0);
+        recorder.record(&event(0, true, 100));
+        recorder.record(&event(0, false, 200));
+        recorder.stop(300);
+
+        recorder.play(1_000);
+        assert_eq!(recorder.poll(1_100), Some(event(0, true, 1_100)));
+        recorder.start(1_150);
+        assert_eq!(recorder.poll(1_150), Some(event(0, false, 1_150)));
+        assert_eq!(recorder.poll(2_000), None);
+        assert!(recorder.is_recording());
+    }
+
+    #[test]
+    fn recording_is_bounded() {
+        let mut recorder: Recorder<2> = Recorder::new();
+        recorder.start(0);
+        assert!(recorder.record(&event(0, true, 1)));
+        assert!(recorder.record(&event(0, false, 2)));
+        assert!(!recorder.record(&event(1, true, 3)));
+
+        recorder.stop(4);
+        recorder.play(0);
+        assert!(recorder.poll(10).is_some());
+        assert!(recorder.poll(10).is_some());
+        assert!(recorder.poll(10).is_none());
+    }
+
+    #[test]
+    fn nothing_is_recorded_or_replayed_while_idle() {
+        let mut recorder: Recorder<2> = Recorder::new();
+        recorder.record(&event(0, true, 1));
+        recorder.play(0);
+        assert_eq!(recorder.poll(10), None);
+    }
+}
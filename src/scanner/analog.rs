@@ -0,0 +1,255 @@
+use embedded_hal::adc::OneShot;
+use embedded_hal::digital::v2::OutputPin;
+use rp2040_hal::{
+    adc::Adc,
+    gpio::{bank0::Gpio26, DynPin, FloatingInput, Pin},
+};
+
+use super::Scanner;
+
+// Readings taken at startup to find the rest position of every key
+const CALIBRATION_SAMPLES: u32 = 16;
+// Time for the multiplexer output to settle after a channel change, in CPU cycles
+const MUX_SETTLE_CYCLES: u32 = 250;
+
+// Key travel, from 0 at rest to 255 fully pressed
+pub type Travel = u8;
+
+// A sensor reading for each key
+pub trait AnalogSource {
+    fn read(&mut self, key: usize) -> u16;
+}
+
+// Sensors behind an analog multiplexer (74HC4067 or alike) read by the RP2040 ADC.
+// Key `i` is on multiplexer channel `i`.
+pub struct Multiplexer<const S: usize> {
+    adc: Adc,
+    input: Pin<Gpio26, FloatingInput>,
+    select: [DynPin; S],
+}
+
+impl<const S: usize> Multiplexer<S> {
+    pub fn new(adc: Adc, input: Pin<Gpio26, FloatingInput>, mut select: [DynPin; S]) -> Self {
+        for pin in select.iter_mut() {
+            pin.into_push_pull_output();
+        }
+
+        Self { adc, input, select }
+    }
+}
+
+impl<const S: usize> AnalogSource for Multiplexer<S> {
+    fn read(&mut self, key: usize) -> u16 {
+        for (bit, pin) in self.select.iter_mut().enumerate() {
+            if key & (1 << bit) != 0 {
+                pin.set_high().unwrap();
+            } else {
+                pin.set_low().unwrap();
+            }
+        }
+        cortex_m::asm::delay(MUX_SETTLE_CYCLES);
+
+        self.adc.read(&mut self.input).unwrap()
+    }
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct AnalogConfig {
+    // Expected reading change from rest to bottom-out, negative for sensors
+    // whose output drops when pressed. The range grows if the keys go further.
+    pub full_travel: i16,
+    // Travel at which a key is pressed
+    pub actuation: Travel,
+    // Travel under which a pressed key is released, lower than `actuation`
+    pub release: Travel,
+    // With rapid trigger, a pressed key is released as soon as it moves up
+    // by this much, and pressed again as soon as it moves down by this much,
+    // until it goes back up past the release point
+    pub rapid_trigger: Option<Travel>,
+}
+
+// Reading range of one key
+#[derive(Clone, Copy, Debug)]
+pub struct Calibration {
+    rest: u16,
+    bottom: u16,
+}
+
+impl Calibration {
+    pub fn new(rest: u16, full_travel: i16) -> Self {
+        Self {
+            rest,
+            bottom: rest.saturating_add_signed(full_travel),
+        }
+    }
+
+    // Map a reading to a travel, widening the range when a key goes past its bottom
+    pub fn travel(&mut self, reading: u16) -> Travel {
+        let pressed_lower = self.bottom < self.rest;
+        if (pressed_lower && reading < self.bottom) || (!pressed_lower && reading > self.bottom) {
+            self.bottom = reading;
+        }
+
+        let range = i32::from(self.bottom) - i32::from(self.rest);
+        if range == 0 {
+            return 0;
+        }
+
+        let travel = (i32::from(reading) - i32::from(self.rest)) * 255 / range;
+        travel.clamp(0, 255) as Travel
+    }
+}
+
+// Actuation state of one key
+#[derive(Clone, Copy, Debug, Default)]
+pub struct KeyTravel {
+    pressed: bool,
+    // Deepest travel since pressed, or shallowest since released
+    extreme: Travel,
+}
+
+impl KeyTravel {
+    pub fn update(&mut self, travel: Travel, config: &AnalogConfig) -> bool {
+        if self.pressed {
+            let lifted = match config.rapid_trigger {
+                Some(sensitivity) => travel.saturating_add(sensitivity) <= self.extreme,
+                None => false,
+            };
+
+            if travel <= config.release || lifted {
+                self.pressed = false;
+                self.extreme = travel;
+            } else {
+                self.extreme = self.extreme.max(travel);
+            }
+        } else {
+            // Released by rapid trigger, without going back up past the release point
+            let rapid_released = config.rapid_trigger.is_some() && self.extreme > config.release;
+
+            let pressed = if rapid_released {
+                let sensitivity = config.rapid_trigger.unwrap_or_default();
+                travel >= self.extreme.saturating_add(sensitivity)
+            } else {
+                travel >= config.actuation
+            };
+
+            if pressed {
+                self.pressed = true;
+                self.extreme = travel;
+            } else {
+                self.extreme = self.extreme.min(travel);
+            }
+        }
+
+        self.pressed
+    }
+}
+
+// Analog keys, such as Hall-effect switches.
+// Key `i` is reported at position (i / COLS, i % COLS).
+pub struct AnalogKeys<A: AnalogSource, const ROWS: usize, const COLS: usize> {
+    source: A,
+    config: AnalogConfig,
+    calibrations: [[Calibration; COLS]; ROWS],
+    keys: [[KeyTravel; COLS]; ROWS],
+}
+
+impl<A: AnalogSource, const ROWS: usize, const COLS: usize> AnalogKeys<A, ROWS, COLS> {
+    // The keys must be at rest while their rest position is measured
+    pub fn new(mut source: A, config: AnalogConfig) -> Self {
+        let calibrations = core::array::from_fn(|r| {
+            core::array::from_fn(|c| {
+                let total: u32 = (0..CALIBRATION_SAMPLES)
+                    .map(|_| u32::from(source.read(r * COLS + c)))
+                    .sum();
+                Calibration::new((total / CALIBRATION_SAMPLES) as u16, config.full_travel)
+            })
+        });
+
+        Self {
+            source,
+            config,
+            calibrations,
+            keys: [[KeyTravel::default(); COLS]; ROWS],
+        }
+    }
+}
+
+impl<A: AnalogSource, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for AnalogKeys<A, ROWS, COLS>
+{
+    fn scan(&mut self) -> [[bool; COLS]; ROWS] {
+        let mut matrix = [[false; COLS]; ROWS];
+
+        for (r, row) in matrix.iter_mut().enumerate() {
+            for (c, pressed) in row.iter_mut().enumerate() {
+                let reading = self.source.read(r * COLS + c);
+                let travel = self.calibrations[r][c].travel(reading);
+                *pressed = self.keys[r][c].update(travel, &self.config);
+            }
+        }
+
+        matrix
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const CONFIG: AnalogConfig = AnalogConfig {
+        full_travel: -1000,
+        actuation: 128,
+        release: 96,
+        rapid_trigger: None,
+    };
+
+    const RAPID_CONFIG: AnalogConfig = AnalogConfig {
+        rapid_trigger: Some(20),
+        ..CONFIG
+    };
+
+    fn run(config: &AnalogConfig, travels: &[Travel]) -> Vec<bool> {
+        let mut key = KeyTravel::default();
+        travels.iter().map(|t| key.update(*t, config)).collect()
+    }
+
+    #[test]
+    fn calibration_maps_readings_to_travel() {
+        let mut calibration = Calibration::new(2000, -1000);
+
+        assert_eq!(calibration.travel(2000), 0);
+        assert_eq!(calibration.travel(2100), 0);
+        assert_eq!(calibration.travel(1500), 127);
+        assert_eq!(calibration.travel(1000), 255);
+
+        // Pressing further than expected widens the range
+        assert_eq!(calibration.travel(800), 255);
+        assert_eq!(calibration.travel(1400), 127);
+    }
+
+    #[test]
+    fn hysteresis_between_actuation_and_release() {
+        let pressed = run(&CONFIG, &[0, 100, 127, 128, 110, 97, 120, 96, 110]);
+        assert_eq!(
+            pressed,
+            [false, false, false, true, true, true, true, false, false]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_follows_direction_changes() {
+        let pressed = run(&RAPID_CONFIG, &[0, 150, 200, 185, 180, 170, 185, 200, 90]);
+        assert_eq!(
+            pressed,
+            [false, true, true, true, false, false, false, true, false]
+        );
+    }
+
+    #[test]
+    fn rapid_trigger_first_press_at_actuation() {
+        // Moving by the sensitivity from rest does not press the key
+        let pressed = run(&RAPID_CONFIG, &[0, 30, 60, 90, 127, 128]);
+        assert_eq!(pressed, [false, false, false, false, false, true]);
+    }
+}
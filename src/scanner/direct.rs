@@ -0,0 +1,53 @@
+use embedded_hal::digital::v2::InputPin;
+use rp2040_hal::gpio::DynPin;
+
+use super::Scanner;
+
+// Pull applied to every pin, the switches short the pin to the other level
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Pull {
+    // Switches to ground, a pressed key reads low
+    Up,
+    // Switches to 3.3V, a pressed key reads high
+    Down,
+}
+
+// One switch per GPIO, without a matrix.
+// Pin `i` is reported at position (i / COLS, i % COLS).
+pub struct DirectPins<const ROWS: usize, const COLS: usize, const N: usize> {
+    pins: [DynPin; N],
+    pull: Pull,
+}
+
+impl<const ROWS: usize, const COLS: usize, const N: usize> DirectPins<ROWS, COLS, N> {
+    pub fn new(mut pins: [DynPin; N], pull: Pull) -> Self {
+        assert!(N <= ROWS * COLS, "more pins than key positions");
+
+        for pin in pins.iter_mut() {
+            match pull {
+                Pull::Up => pin.into_pull_up_input(),
+                Pull::Down => pin.into_pull_down_input(),
+            }
+        }
+
+        Self { pins, pull }
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize, const N: usize> Scanner<ROWS, COLS>
+    for DirectPins<ROWS, COLS, N>
+{
+    fn scan(&mut self) -> [[bool; COLS]; ROWS] {
+        let mut matrix = [[false; COLS]; ROWS];
+
+        for (i, pin) in self.pins.iter().enumerate() {
+            matrix[i / COLS][i % COLS] = match self.pull {
+                Pull::Up => pin.is_low().unwrap(),
+                Pull::Down => pin.is_high().unwrap(),
+            };
+        }
+
+        matrix
+    }
+}
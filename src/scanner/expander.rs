@@ -0,0 +1,258 @@
+use embedded_hal::blocking::i2c::{Write, WriteRead};
+use embedded_hal::digital::v2::InputPin;
+
+use super::matrix::DiodeDirection;
+use super::Scanner;
+
+// Supported I2C port expanders.
+// Both have 16 pins in two 8-bit ports, pin 0-7 on the first port and
+// 8-15 on the second, and internal pull-ups on their inputs.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Chip {
+    Mcp23017,
+    Pca9555,
+}
+
+// MCP23017 registers, with IOCON.BANK = 0 so that port A and B registers
+// are next to each other and can be accessed as one 16-bit register
+const MCP23017_IODIR: u8 = 0x00;
+const MCP23017_GPINTEN: u8 = 0x04;
+const MCP23017_INTCON: u8 = 0x08;
+const MCP23017_IOCON: u8 = 0x0A;
+const MCP23017_GPPU: u8 = 0x0C;
+const MCP23017_GPIO: u8 = 0x12;
+const MCP23017_OLAT: u8 = 0x14;
+// INTA and INTB both signal a change on any of the two ports
+const MCP23017_IOCON_MIRROR: u8 = 1 << 6;
+
+// PCA9555 registers
+const PCA9555_INPUT: u8 = 0x00;
+const PCA9555_OUTPUT: u8 = 0x02;
+const PCA9555_CONFIG: u8 = 0x06;
+
+// How the switches are wired to the expander pins.
+// Switches are active low, using the expander pull-ups.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug)]
+pub enum ExpanderMode {
+    // A diode matrix, the strobed lines are driven low one at a time
+    Matrix {
+        rows: &'static [u8],
+        cols: &'static [u8],
+        direction: DiodeDirection,
+    },
+    // One switch to ground per pin, pin `pins[i]` is reported
+    // at position (i / COLS, i % COLS)
+    Direct {
+        pins: &'static [u8],
+    },
+}
+
+// While a key is held in matrix mode, the matrix is read on one scan out of
+// this many. A full read is about 10 I2C transfers, close to 1ms at 400kHz.
+const HELD_SCAN_INTERVAL: u8 = 10;
+
+// Key matrix behind an I2C port expander.
+// The expander is only read after its interrupt output signals a change,
+// or now and then while a key is held in matrix mode since a release can be
+// hidden by another key held on the same line.
+pub struct Expander<I2C, INT, const ROWS: usize, const COLS: usize> {
+    i2c: I2C,
+    int: INT,
+    chip: Chip,
+    address: u8,
+    mode: ExpanderMode,
+    // Pins driven by the expander, the strobed lines in matrix mode
+    outputs: u16,
+    matrix: [[bool; COLS]; ROWS],
+    // The state has to be read even without an interrupt, at startup
+    // or after a failed transfer
+    stale: bool,
+    // Scans since the last read while a key is held
+    held_scans: u8,
+}
+
+fn mask(pins: &[u8]) -> u16 {
+    pins.iter().fold(0, |mask, pin| mask | 1 << pin)
+}
+
+impl<I2C, INT, E, const ROWS: usize, const COLS: usize> Expander<I2C, INT, ROWS, COLS>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    INT: InputPin,
+{
+    // `int` is the expander interrupt output, active low
+    pub fn new(i2c: I2C, int: INT, chip: Chip, address: u8, mode: ExpanderMode) -> Result<Self, E> {
+        let (outputs, inputs) = match mode {
+            ExpanderMode::Matrix {
+                rows,
+                cols,
+                direction,
+            } => {
+                assert!(rows.len() <= ROWS && cols.len() <= COLS);
+                match direction {
+                    DiodeDirection::Col2Row => (mask(cols), mask(rows)),
+                    DiodeDirection::Row2Col => (mask(rows), mask(cols)),
+                }
+            }
+            ExpanderMode::Direct { pins } => {
+                assert!(pins.len() <= ROWS * COLS, "more pins than key positions");
+                (0, mask(pins))
+            }
+        };
+
+        let mut expander = Self {
+            i2c,
+            int,
+            chip,
+            address,
+            mode,
+            outputs,
+            matrix: [[false; COLS]; ROWS],
+            stale: true,
+            held_scans: 0,
+        };
+
+        // All the strobed lines are driven low between scans, so that
+        // pressing any key changes an input and raises the interrupt
+        expander.write_outputs(0)?;
+
+        match chip {
+            Chip::Mcp23017 => {
+                expander.write_register(MCP23017_IOCON, MCP23017_IOCON_MIRROR)?;
+                expander.write_registers(MCP23017_IODIR, !outputs)?;
+                expander.write_registers(MCP23017_GPPU, inputs)?;
+                // Interrupt on any change of the inputs
+                expander.write_registers(MCP23017_INTCON, 0)?;
+                expander.write_registers(MCP23017_GPINTEN, inputs)?;
+            }
+            Chip::Pca9555 => {
+                // The PCA9555 signals any change of its inputs, and its pull-ups
+                // are always enabled
+                expander.write_registers(PCA9555_CONFIG, !outputs)?;
+            }
+        }
+
+        // Clear any interrupt raised while configuring
+        expander.read_inputs()?;
+
+        Ok(expander)
+    }
+
+    fn write_register(&mut self, register: u8, value: u8) -> Result<(), E> {
+        self.i2c.write(self.address, &[register, value])
+    }
+
+    // Write a pair of registers, first port first
+    fn write_registers(&mut self, register: u8, value: u16) -> Result<(), E> {
+        let [low, high] = value.to_le_bytes();
+        self.i2c.write(self.address, &[register, low, high])
+    }
+
+    fn write_outputs(&mut self, value: u16) -> Result<(), E> {
+        match self.chip {
+            Chip::Mcp23017 => self.write_registers(MCP23017_OLAT, value),
+            Chip::Pca9555 => self.write_registers(PCA9555_OUTPUT, value),
+        }
+    }
+
+    // Reading the inputs also clears the expander interrupt
+    fn read_inputs(&mut self) -> Result<u16, E> {
+        let register = match self.chip {
+            Chip::Mcp23017 => MCP23017_GPIO,
+            Chip::Pca9555 => PCA9555_INPUT,
+        };
+
+        let mut value = [0; 2];
+        self.i2c.write_read(self.address, &[register], &mut value)?;
+        Ok(u16::from_le_bytes(value))
+    }
+
+    fn read_direct(&mut self, pins: &[u8]) -> Result<[[bool; COLS]; ROWS], E> {
+        let inputs = self.read_inputs()?;
+        let mut matrix = [[false; COLS]; ROWS];
+
+        for (i, pin) in pins.iter().enumerate() {
+            matrix[i / COLS][i % COLS] = inputs & (1 << pin) == 0;
+        }
+
+        Ok(matrix)
+    }
+
+    // Each I2C transfer takes tens of microseconds, long enough
+    // for the lines to settle without waiting any longer
+    fn read_matrix(
+        &mut self,
+        rows: &[u8],
+        cols: &[u8],
+        direction: DiodeDirection,
+    ) -> Result<[[bool; COLS]; ROWS], E> {
+        let mut matrix = [[false; COLS]; ROWS];
+
+        let (strobes, reads) = match direction {
+            DiodeDirection::Col2Row => (cols, rows),
+            DiodeDirection::Row2Col => (rows, cols),
+        };
+
+        for (s, strobe) in strobes.iter().enumerate() {
+            self.write_outputs(self.outputs & !(1 << strobe))?;
+            let inputs = self.read_inputs()?;
+
+            for (r, read) in reads.iter().enumerate() {
+                let (row, col) = match direction {
+                    DiodeDirection::Col2Row => (r, s),
+                    DiodeDirection::Row2Col => (s, r),
+                };
+                matrix[row][col] = inputs & (1 << read) == 0;
+            }
+        }
+
+        // Back to all lines strobed, and clear the interrupt raised by the scan itself
+        self.write_outputs(0)?;
+        self.read_inputs()?;
+
+        Ok(matrix)
+    }
+}
+
+impl<I2C, INT, E, const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS>
+    for Expander<I2C, INT, ROWS, COLS>
+where
+    I2C: Write<Error = E> + WriteRead<Error = E>,
+    INT: InputPin,
+{
+    fn scan(&mut self) -> [[bool; COLS]; ROWS] {
+        let changed = !matches!(self.int.is_high(), Ok(true));
+        let mut held = false;
+        if self.matrix.iter().flatten().any(|pressed| *pressed) {
+            self.held_scans = self.held_scans.saturating_add(1);
+            held = self.held_scans >= HELD_SCAN_INTERVAL;
+        }
+
+        let matrix = match self.mode {
+            ExpanderMode::Direct { pins } if changed || self.stale => self.read_direct(pins),
+            ExpanderMode::Matrix {
+                rows,
+                cols,
+                direction,
+            } if changed || held || self.stale => self.read_matrix(rows, cols, direction),
+            // Nothing moved since the last read
+            _ => return self.matrix,
+        };
+
+        match matrix {
+            Ok(matrix) => {
+                self.matrix = matrix;
+                self.stale = false;
+                self.held_scans = 0;
+            }
+            Err(_) => {
+                defmt::warn!("I2C expander read failed");
+                self.stale = true;
+            }
+        }
+
+        self.matrix
+    }
+}
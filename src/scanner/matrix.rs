@@ -0,0 +1,115 @@
+use embedded_hal::digital::v2::{InputPin, OutputPin};
+use rp2040_hal::{gpio::DynPin, Timer};
+
+use super::Scanner;
+use crate::MATRIX_SCAN_US;
+
+// Which side of the switches the diodes let the current flow from.
+// The lines on that side are strobed, the lines on the other side are read.
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiodeDirection {
+    Col2Row,
+    Row2Col,
+}
+
+// Level of a strobed line, and of a read line when its key is pressed
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Polarity {
+    // Lines are strobed high, read lines are pulled down
+    ActiveHigh,
+    // Lines are strobed low, read lines are pulled up
+    ActiveLow,
+}
+
+// Key matrix scanned by the CPU, one strobed line at a time
+pub struct Matrix<const ROWS: usize, const COLS: usize> {
+    rows: [DynPin; ROWS],
+    cols: [DynPin; COLS],
+    direction: DiodeDirection,
+    polarity: Polarity,
+    timer: &'static Timer,
+}
+
+impl<const ROWS: usize, const COLS: usize> Matrix<ROWS, COLS> {
+    pub fn new(
+        mut rows: [DynPin; ROWS],
+        mut cols: [DynPin; COLS],
+        direction: DiodeDirection,
+        polarity: Polarity,
+        timer: &'static Timer,
+    ) -> Self {
+        let (strobes, reads): (&mut [DynPin], &mut [DynPin]) = match direction {
+            DiodeDirection::Col2Row => (&mut cols, &mut rows),
+            DiodeDirection::Row2Col => (&mut rows, &mut cols),
+        };
+
+        for pin in strobes.iter_mut() {
+            pin.into_push_pull_output();
+            set_active(pin, polarity, false);
+        }
+
+        for pin in reads.iter_mut() {
+            match polarity {
+                Polarity::ActiveHigh => pin.into_pull_down_input(),
+                Polarity::ActiveLow => pin.into_pull_up_input(),
+            }
+        }
+
+        Self {
+            rows,
+            cols,
+            direction,
+            polarity,
+            timer,
+        }
+    }
+}
+
+fn set_active(pin: &mut DynPin, polarity: Polarity, active: bool) {
+    if active == (polarity == Polarity::ActiveHigh) {
+        pin.set_high().unwrap();
+    } else {
+        pin.set_low().unwrap();
+    }
+}
+
+fn is_active(pin: &DynPin, polarity: Polarity) -> bool {
+    pin.is_high().unwrap() == (polarity == Polarity::ActiveHigh)
+}
+
+// Busy wait using the free running timer
+fn wait_us(timer: &Timer, us: u32) {
+    let start = timer.get_counter();
+    while (timer.get_counter() - start).to_micros() < us.into() {}
+}
+
+impl<const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for Matrix<ROWS, COLS> {
+    fn scan(&mut self) -> [[bool; COLS]; ROWS] {
+        let mut matrix = [[false; COLS]; ROWS];
+
+        let (strobes, reads): (&mut [DynPin], &[DynPin]) = match self.direction {
+            DiodeDirection::Col2Row => (&mut self.cols, &self.rows),
+            DiodeDirection::Row2Col => (&mut self.rows, &self.cols),
+        };
+
+        for (s, strobe) in strobes.iter_mut().enumerate() {
+            set_active(strobe, self.polarity, true);
+            wait_us(self.timer, MATRIX_SCAN_US);
+
+            for (r, read) in reads.iter().enumerate() {
+                let (row, col) = match self.direction {
+                    DiodeDirection::Col2Row => (r, s),
+                    DiodeDirection::Row2Col => (s, r),
+                };
+                matrix[row][col] = is_active(read, self.polarity);
+            }
+
+            set_active(strobe, self.polarity, false);
+            wait_us(self.timer, MATRIX_SCAN_US);
+        }
+
+        matrix
+    }
+}
@@ -0,0 +1,197 @@
+#[cfg(not(feature = "expander-scanner"))]
+use core::cell::RefCell;
+
+#[cfg(not(feature = "expander-scanner"))]
+use cortex_m::interrupt::Mutex;
+use fugit::ExtU32;
+use heapless::spsc::{Producer, Queue};
+#[cfg(not(feature = "expander-scanner"))]
+use rp2040_hal::{
+    pac::{self, interrupt},
+    timer::{Alarm, Alarm0},
+};
+use rp2040_hal::{timer::Instant, Timer};
+
+#[cfg(feature = "analog-scanner")]
+use crate::ANALOG_MUX_SELECT_PINS;
+#[cfg(feature = "direct-scanner")]
+use crate::DIRECT_PINS;
+#[cfg(feature = "expander-scanner")]
+use crate::{ExpanderI2c, ExpanderInt};
+use crate::{COLS, ROWS};
+
+#[cfg(feature = "analog-scanner")]
+pub mod analog;
+#[cfg(feature = "direct-scanner")]
+pub mod direct;
+#[cfg(feature = "expander-scanner")]
+pub mod expander;
+// The PIO and expander scanners share the wiring options of the CPU matrix scanner
+#[cfg(not(any(feature = "direct-scanner", feature = "analog-scanner")))]
+#[cfg_attr(
+    any(feature = "pio-scanner", feature = "expander-scanner"),
+    allow(dead_code)
+)]
+pub mod matrix;
+#[cfg(feature = "pio-scanner")]
+pub mod pio;
+
+const _: () = assert!(
+    cfg!(feature = "pio-scanner") as u8
+        + cfg!(feature = "direct-scanner") as u8
+        + cfg!(feature = "expander-scanner") as u8
+        + cfg!(feature = "analog-scanner") as u8
+        <= 1,
+    "only one scanner feature can be enabled"
+);
+
+// A way of reading the state of every key of the board,
+// indexed as `[row][col]`
+pub trait Scanner<const ROWS: usize, const COLS: usize> {
+    fn scan(&mut self) -> [[bool; COLS]; ROWS];
+}
+
+// The scanner backend is selected at compile time
+#[cfg(not(any(
+    feature = "pio-scanner",
+    feature = "direct-scanner",
+    feature = "expander-scanner",
+    feature = "analog-scanner"
+)))]
+pub type BoardScanner = matrix::Matrix<ROWS, COLS>;
+#[cfg(feature = "pio-scanner")]
+pub type BoardScanner = pio::PioMatrix<ROWS, COLS>;
+#[cfg(feature = "direct-scanner")]
+pub type BoardScanner = direct::DirectPins<ROWS, COLS, DIRECT_PINS>;
+#[cfg(feature = "expander-scanner")]
+pub type BoardScanner = expander::Expander<ExpanderI2c, ExpanderInt, ROWS, COLS>;
+#[cfg(feature = "analog-scanner")]
+pub type BoardScanner = analog::AnalogKeys<analog::Multiplexer<ANALOG_MUX_SELECT_PINS>, ROWS, COLS>;
+
+// Number of scans that can wait for the main loop, plus one
+const SCAN_QUEUE_SIZE: usize = 8;
+
+// The state of every key at a given time, in microseconds since boot
+pub struct Scan {
+    pub matrix: [[bool; COLS]; ROWS],
+    pub timestamp: u64,
+}
+
+// Lock-free buffer between the scan interrupt (producer) and the main loop (consumer)
+pub type ScanQueue = Queue<Scan, SCAN_QUEUE_SIZE>;
+
+// Everything the scan interrupt needs, handed over by `start`
+#[cfg(not(feature = "expander-scanner"))]
+struct ScanResources {
+    scanner: BoardScanner,
+    timer: &'static Timer,
+    alarm: Alarm0,
+    period_us: u32,
+    next_scan: Instant,
+    scans: Producer<'static, Scan, SCAN_QUEUE_SIZE>,
+}
+
+#[cfg(not(feature = "expander-scanner"))]
+static SCAN_RESOURCES: Mutex<RefCell<Option<ScanResources>>> = Mutex::new(RefCell::new(None));
+
+// Scan the matrix `frequency_hz` times per second from the TIMER_IRQ_0 interrupt
+#[cfg(not(feature = "expander-scanner"))]
+pub fn start(
+    scanner: BoardScanner,
+    timer: &'static Timer,
+    mut alarm: Alarm0,
+    frequency_hz: u32,
+    scans: Producer<'static, Scan, SCAN_QUEUE_SIZE>,
+) {
+    let period_us = 1_000_000 / frequency_hz;
+    let next_scan = timer.get_counter() + period_us.micros();
+
+    alarm.schedule_at(next_scan).unwrap();
+    alarm.enable_interrupt();
+
+    cortex_m::interrupt::free(|cs| {
+        SCAN_RESOURCES.borrow(cs).replace(Some(ScanResources {
+            scanner,
+            timer,
+            alarm,
+            period_us,
+            next_scan,
+            scans,
+        }));
+    });
+
+    // Safety: the interrupt only touches the resources moved into SCAN_RESOURCES
+    unsafe { pac::NVIC::unmask(pac::Interrupt::TIMER_IRQ_0) };
+}
+
+#[cfg(not(feature = "expander-scanner"))]
+#[interrupt]
+fn TIMER_IRQ_0() {
+    cortex_m::interrupt::free(|cs| {
+        if let Some(resources) = SCAN_RESOURCES.borrow(cs).borrow_mut().as_mut() {
+            resources.alarm.clear_interrupt();
+
+            // Schedule from the previous deadline so the scan rate does not drift
+            resources.next_scan += resources.period_us.micros();
+            resources.alarm.schedule_at(resources.next_scan).unwrap();
+
+            let timestamp = resources.timer.get_counter().ticks();
+            let matrix = resources.scanner.scan();
+
+            // A full queue means the main loop is behind: drop this scan,
+            // the next one will carry the same information
+            let _ = resources.scans.enqueue(Scan { matrix, timestamp });
+        }
+    });
+}
+
+// I2C transfers to the expander take too long for the scan interrupt, which
+// masks every other interrupt: the expander is scanned from the main loop,
+// through the same queue
+#[cfg(feature = "expander-scanner")]
+pub struct LoopScanner {
+    scanner: BoardScanner,
+    timer: &'static Timer,
+    period_us: u32,
+    next_scan: Instant,
+    scans: Producer<'static, Scan, SCAN_QUEUE_SIZE>,
+}
+
+#[cfg(feature = "expander-scanner")]
+impl LoopScanner {
+    pub fn new(
+        scanner: BoardScanner,
+        timer: &'static Timer,
+        frequency_hz: u32,
+        scans: Producer<'static, Scan, SCAN_QUEUE_SIZE>,
+    ) -> Self {
+        let period_us = 1_000_000 / frequency_hz;
+        Self {
+            scanner,
+            timer,
+            period_us,
+            next_scan: timer.get_counter() + period_us.micros(),
+            scans,
+        }
+    }
+
+    // Scan once the period is over, to be called on every iteration of the main loop
+    pub fn poll(&mut self) {
+        let now = self.timer.get_counter();
+        if now < self.next_scan {
+            return;
+        }
+
+        // Scans missed while the main loop was busy are skipped, not caught up
+        self.next_scan += self.period_us.micros();
+        if self.next_scan <= now {
+            self.next_scan = now + self.period_us.micros();
+        }
+
+        let matrix = self.scanner.scan();
+        let _ = self.scans.enqueue(Scan {
+            matrix,
+            timestamp: now.ticks(),
+        });
+    }
+}
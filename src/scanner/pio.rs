@@ -0,0 +1,185 @@
+use ::pio::{
+    Assembler, InSource, JmpCondition, MovDestination, MovOperation, MovSource, OutDestination,
+    SetDestination,
+};
+use rp2040_hal::{
+    gpio::{DynFunction, DynPin, DynPinMode},
+    pac::{self, PIO0},
+    pio::{PIOBuilder, PIOExt, PinDir, Running, Rx, ShiftDirection, StateMachine, Tx, SM0},
+};
+
+use super::matrix::{DiodeDirection, Polarity};
+use super::Scanner;
+use crate::MATRIX_SCAN_US;
+
+// The state machine runs at 1 MHz so one cycle is one microsecond
+const PIO_FREQUENCY_HZ: u32 = 1_000_000;
+
+// Settle time after driving a strobed line, as instruction delay cycles
+const _: () = assert!(MATRIX_SCAN_US >= 1 && MATRIX_SCAN_US <= 32);
+const SETTLE_CYCLES: u8 = (MATRIX_SCAN_US - 1) as u8;
+
+// Key matrix scanned by a PIO state machine.
+// Each scan is requested by writing the strobe patterns to the TX FIFO,
+// the state machine then drives every strobed line in turn, samples the
+// read lines and pushes the packed snapshot to the RX FIFO.
+pub struct PioMatrix<const ROWS: usize, const COLS: usize> {
+    _sm: StateMachine<(PIO0, SM0), Running>,
+    rx: Rx<(PIO0, SM0)>,
+    tx: Tx<(PIO0, SM0)>,
+    direction: DiodeDirection,
+    polarity: Polarity,
+    // One pattern per `out`, starting with the first strobed line
+    strobes: u32,
+    matrix: [[bool; COLS]; ROWS],
+}
+
+impl<const ROWS: usize, const COLS: usize> PioMatrix<ROWS, COLS> {
+    // The read lines must be consecutive GPIOs, and the strobed lines
+    // must fit in a span of 32 / (number of strobed lines) GPIOs
+    pub fn new(
+        mut rows: [DynPin; ROWS],
+        mut cols: [DynPin; COLS],
+        direction: DiodeDirection,
+        polarity: Polarity,
+        pio0: PIO0,
+        system_clock_hz: u32,
+        resets: &mut pac::RESETS,
+    ) -> Self {
+        // A whole matrix has to fit in one RX FIFO word
+        assert!(
+            ROWS * COLS <= 32,
+            "the matrix does not fit in a PIO snapshot"
+        );
+
+        let (strobes, reads): (&mut [DynPin], &mut [DynPin]) = match direction {
+            DiodeDirection::Col2Row => (&mut cols, &mut rows),
+            DiodeDirection::Row2Col => (&mut rows, &mut cols),
+        };
+
+        let read_base = reads[0].id().num;
+        for (r, read) in reads.iter_mut().enumerate() {
+            assert_eq!(
+                read.id().num,
+                read_base + r as u8,
+                "read lines are not consecutive"
+            );
+            read.try_into_mode(DynPinMode::Function(DynFunction::Pio0))
+                .unwrap();
+
+            // Selecting the PIO function clears the pad pulls, put them back
+            // Safety: only this line's pad is modified
+            let pads = unsafe { &*pac::PADS_BANK0::ptr() };
+            pads.gpio[read.id().num as usize].modify(|_, w| match polarity {
+                Polarity::ActiveHigh => w.pde().set_bit().pue().clear_bit(),
+                Polarity::ActiveLow => w.pde().clear_bit().pue().set_bit(),
+            });
+        }
+
+        let strobe_base = strobes.iter().map(|pin| pin.id().num).min().unwrap();
+        let strobe_span = strobes.iter().map(|pin| pin.id().num).max().unwrap() - strobe_base + 1;
+        assert!(
+            strobe_span as usize * strobes.len() <= 32,
+            "strobed lines are too far apart"
+        );
+
+        let span_mask = (1u32 << strobe_span) - 1;
+        let mut patterns = 0;
+        for (s, strobe) in strobes.iter_mut().enumerate() {
+            strobe
+                .try_into_mode(DynPinMode::Function(DynFunction::Pio0))
+                .unwrap();
+
+            let mut pattern = 1 << (strobe.id().num - strobe_base);
+            if polarity == Polarity::ActiveLow {
+                pattern = !pattern & span_mask;
+            }
+            patterns |= pattern << (s * strobe_span as usize);
+        }
+
+        // Level of the strobed lines between two scans
+        let idle = match polarity {
+            Polarity::ActiveHigh => MovOperation::None,
+            Polarity::ActiveLow => MovOperation::Invert,
+        };
+
+        let mut a = Assembler::<{ ::pio::RP2040_MAX_PROGRAM_SIZE }>::new();
+        let mut wrap_target = a.label();
+        let mut wrap_source = a.label();
+        let mut strobe = a.label();
+        a.bind(&mut wrap_target);
+        // Wait for a scan request
+        a.pull(false, true);
+        a.set(SetDestination::X, (strobes.len() - 1) as u8);
+        a.bind(&mut strobe);
+        // Drive one line and let the read lines settle before sampling them
+        a.out_with_delay(OutDestination::PINS, strobe_span, SETTLE_CYCLES);
+        a.r#in(InSource::PINS, reads.len() as u8);
+        a.jmp(JmpCondition::XDecNonZero, &mut strobe);
+        a.mov(MovDestination::PINS, idle, MovSource::NULL);
+        a.bind(&mut wrap_source);
+        a.push(false, false);
+        let program = a.assemble_with_wrap(wrap_source, wrap_target);
+
+        let (mut pio, sm0, _, _, _) = pio0.split(resets);
+        let installed = pio.install(&program).unwrap();
+        let (mut sm, rx, tx) = PIOBuilder::from_program(installed)
+            .out_pins(strobe_base, strobe_span)
+            .in_pin_base(read_base)
+            .out_shift_direction(ShiftDirection::Right)
+            .in_shift_direction(ShiftDirection::Right)
+            .clock_divisor_fixed_point((system_clock_hz / PIO_FREQUENCY_HZ) as u16, 0)
+            .build(sm0);
+        sm.set_pindirs(strobes.iter().map(|pin| (pin.id().num, PinDir::Output)));
+
+        Self {
+            _sm: sm.start(),
+            rx,
+            tx,
+            direction,
+            polarity,
+            strobes: patterns,
+            matrix: [[false; COLS]; ROWS],
+        }
+    }
+
+    // With the ISR shifting right, the first strobed line sampled ends up
+    // in the lowest bits once the unused bits are dropped
+    fn unpack(&self, word: u32) -> [[bool; COLS]; ROWS] {
+        let (strobe_count, read_count) = match self.direction {
+            DiodeDirection::Col2Row => (COLS, ROWS),
+            DiodeDirection::Row2Col => (ROWS, COLS),
+        };
+
+        let mut word = word >> (32 - strobe_count * read_count);
+        if self.polarity == Polarity::ActiveLow {
+            word = !word;
+        }
+
+        let mut matrix = [[false; COLS]; ROWS];
+        for (r, row) in matrix.iter_mut().enumerate() {
+            for (c, key) in row.iter_mut().enumerate() {
+                let bit = match self.direction {
+                    DiodeDirection::Col2Row => c * read_count + r,
+                    DiodeDirection::Row2Col => r * read_count + c,
+                };
+                *key = word & (1 << bit) != 0;
+            }
+        }
+
+        matrix
+    }
+}
+
+impl<const ROWS: usize, const COLS: usize> Scanner<ROWS, COLS> for PioMatrix<ROWS, COLS> {
+    // Returns the snapshot of the previous request, and requests a new one
+    fn scan(&mut self) -> [[bool; COLS]; ROWS] {
+        while let Some(word) = self.rx.read() {
+            self.matrix = self.unpack(word);
+        }
+
+        self.tx.write(self.strobes);
+
+        self.matrix
+    }
+}
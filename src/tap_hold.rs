@@ -0,0 +1,717 @@
+// Decide whether tap-hold keys are tapped or held, and count the taps of
+// tap-dance keys.
+// Key events go through here on their way from the event queue to the actions
+// of the main loop. While a tap-hold key is undecided, the events after it wait
+// in the queue, so they are resolved on the layers the decision leads to.
+// A tap-dance key ends when its term passes without a tap, or another key is
+// used: its action is then returned before the events of that key.
+// Combos, see src/combo.rs, are found here too, before the keys are resolved
+// on the layers.
+use heapless::Deque;
+
+use crate::combo::{Combos, MAX_COMBO_KEYS};
+use crate::event::{EventQueue, KeyEvent};
+use crate::keypad::{Action, Combo, Press, TapDance, TapHold};
+use crate::layers::Layers;
+
+// What makes a tap-hold key a hold before the tapping term is over
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Flavor {
+    // Nothing, the key is a tap if released within the term
+    Timeout,
+    // Another key pressed and released while the key is held
+    PermissiveHold,
+    // Another key pressed while the key is held
+    HoldOnOtherKeyPress,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub struct TapHoldConfig {
+    // A key held this long is a hold
+    pub tapping_term_us: u64,
+    pub flavor: Flavor,
+    // Tap a key held past the term anyway if no other key was pressed meanwhile
+    pub retro_tapping: bool,
+    // A tap-dance key ends once it is not pressed or released for this long
+    pub tap_dance_term_us: u64,
+    // Keys of a combo are pressed within this time of the first one
+    pub combo_term_us: u64,
+}
+
+// A key event and the action it resolved to
+#[derive(Clone, Copy)]
+pub struct Resolved {
+    pub event: KeyEvent,
+    pub action: Action,
+    // Added for a retro tap or the end of a tap dance,
+    // the event is not one of the pad
+    pub synthetic: bool,
+    // Prefix of the layer the action comes from, sent before its macro
+    pub prefix: Option<Press>,
+}
+
+// A tap-dance key being tapped
+#[derive(Clone, Copy)]
+struct Dance {
+    keys: &'static TapDance,
+    // Last press or release of the key
+    event: KeyEvent,
+    // Presses so far
+    taps: usize,
+    prefix: Option<Press>,
+}
+
+pub struct TapHoldKeys<const ROWS: usize, const COLS: usize> {
+    config: TapHoldConfig,
+    combos: Combos<ROWS, COLS>,
+    // Press of the tap-hold key waiting for a decision
+    pending: Option<(KeyEvent, &'static TapHold)>,
+    // Action each held tap-hold key was decided as, undone on release
+    decided: [[Option<Action>; COLS]; ROWS],
+    // Tap action of the key held past the term, until another key is pressed
+    retro: Option<(KeyEvent, Action, Option<Press>)>,
+    // Tap-dance key being tapped, until its action is known
+    dance: Option<Dance>,
+    // Synthetic press and release, or presses of a combo, waiting to be returned
+    resolved: Deque<Resolved, MAX_COMBO_KEYS>,
+}
+
+impl<const ROWS: usize, const COLS: usize> TapHoldKeys<ROWS, COLS> {
+    pub fn new(config: TapHoldConfig, combos: &'static [Combo]) -> Self {
+        Self {
+            config,
+            combos: Combos::new(combos, config.combo_term_us),
+            pending: None,
+            decided: [[None; COLS]; ROWS],
+            retro: None,
+            dance: None,
+            resolved: Deque::new(),
+        }
+    }
+
+    // The next event of the queue and its action, once no tap-hold key or
+    // combo stands in the way. Returns None when the queue is empty or the next
+    // events wait for a decision.
+    // While a leader sequence is entered, presses only give their position:
+    // they are returned with no action, and change no layer.
+    pub fn next(
+        &mut self,
+        now: u64,
+        events: &mut EventQueue,
+        layers: &mut Layers<ROWS, COLS>,
+        leading: bool,
+    ) -> Option<Resolved> {
+        if let Some(resolved) = self.resolved.pop_front() {
+            return Some(resolved);
+        }
+
+        if let Some((press, keys)) = self.pending {
+            let hold = self.decide(&press, now, events)?;
+            self.pending = None;
+
+            let action = if hold { keys.hold } else { keys.tap };
+            let prefix = layers.prefix(press.row, press.col);
+            self.decided[press.row as usize][press.col as usize] = Some(action);
+            if hold && self.config.retro_tapping {
+                self.retro = Some((press, keys.tap, prefix));
+            }
+            layers.apply(action, true);
+            return Some(resolved(press, action, prefix));
+        }
+
+        if let Some(dance) = self.dance {
+            let deadline = dance.event.timestamp + self.config.tap_dance_term_us;
+            let danced = match events.front() {
+                Some(event) => {
+                    (event.row, event.col) == (dance.event.row, dance.event.col)
+                        && event.timestamp < deadline
+                }
+                None if now < deadline => return None,
+                None => false,
+            };
+            if !danced {
+                self.end_dance(dance, layers);
+                return self.resolved.pop_front();
+            }
+        }
+
+        if leading && events.front().is_some_and(|event| event.pressed) {
+            let event = events.pop_front()?;
+            return Some(resolved(event, Action::None, None));
+        }
+
+        match self.combos.find(events, now) {
+            None => return None,
+            Some(Some((index, presses))) => {
+                return Some(self.press_combo(index, presses, events, layers))
+            }
+            Some(None) => {}
+        }
+
+        let event = events.pop_front()?;
+        if let Some(action) = self.combos.release(&event) {
+            layers.apply(action, false);
+            return Some(resolved(event, action, None));
+        }
+
+        let action = layers.event(&event);
+        let prefix = layers.prefix(event.row, event.col);
+        if event.pressed {
+            self.retro = None;
+        }
+
+        match action {
+            Action::TapHold(keys) if event.pressed => {
+                self.pending = Some((event, keys));
+                self.next(now, events, layers, leading)
+            }
+            // The events of a tap-dance key only count taps, they are returned
+            // with no action so they are still recorded
+            Action::TapDance(keys) if event.pressed || self.dance.is_some() => {
+                let (taps, prefix) = match self.dance {
+                    Some(dance) => (dance.taps + event.pressed as usize, dance.prefix),
+                    None => (1, prefix),
+                };
+                let dance = Dance {
+                    keys,
+                    event,
+                    taps,
+                    prefix,
+                };
+                self.dance = Some(dance);
+
+                // No action for more taps, no need to wait for them
+                if !event.pressed && taps >= keys.taps.len().max(keys.holds.len()) {
+                    self.end_dance(dance, layers);
+                }
+                Some(resolved(event, Action::None, None))
+            }
+            Action::TapHold(_) | Action::TapDance(_) => {
+                let Some(action) = self.decided[event.row as usize][event.col as usize].take()
+                else {
+                    return Some(resolved(event, Action::None, None));
+                };
+                layers.apply(action, false);
+
+                if let Some((press, tap, prefix)) = self.retro.take() {
+                    if (press.row, press.col) == (event.row, event.col) {
+                        self.tap(event, tap, prefix, layers);
+                    }
+                }
+                Some(resolved(event, action, None))
+            }
+            _ => Some(resolved(event, action, prefix)),
+        }
+    }
+
+    // The presses of a combo are returned too so they are still recorded,
+    // the first one with the action of the combo
+    fn press_combo(
+        &mut self,
+        index: usize,
+        presses: usize,
+        events: &mut EventQueue,
+        layers: &mut Layers<ROWS, COLS>,
+    ) -> Resolved {
+        let action = self.combos.press(index);
+        self.retro = None;
+        layers.apply(action, true);
+
+        let first = events.pop_front().unwrap();
+        for _ in 1..presses {
+            let event = events.pop_front().unwrap();
+            let _ = self.resolved.push_back(resolved(event, Action::None, None));
+        }
+        // Combos do not belong to a layer, they have no prefix
+        resolved(first, action, None)
+    }
+
+    // Queue the action of a tap-dance key once it ended: the hold action for
+    // its number of taps if it is still held, or else the tap action, tapped
+    fn end_dance(&mut self, dance: Dance, layers: &mut Layers<ROWS, COLS>) {
+        self.dance = None;
+
+        let index = dance.taps - 1;
+        let event = dance.event;
+        let hold = dance.keys.holds.get(index).filter(|_| event.pressed);
+        if let Some(&action) = hold {
+            self.decided[event.row as usize][event.col as usize] = Some(action);
+            layers.apply(action, true);
+            let _ = self.resolved.push_back(Resolved {
+                event,
+                action,
+                synthetic: true,
+                prefix: dance.prefix,
+            });
+            return;
+        }
+
+        let action = dance.keys.taps.get(index).copied().unwrap_or(Action::None);
+        self.tap(event, action, dance.prefix, layers);
+    }
+
+    // Queue a synthetic press and release of an action, and apply them to the layers
+    fn tap(
+        &mut self,
+        event: KeyEvent,
+        action: Action,
+        prefix: Option<Press>,
+        layers: &mut Layers<ROWS, COLS>,
+    ) {
+        layers.apply(action, true);
+        layers.apply(action, false);
+        for pressed in [true, false] {
+            let _ = self.resolved.push_back(Resolved {
+                event: KeyEvent { pressed, ..event },
+                action,
+                synthetic: true,
+                prefix,
+            });
+        }
+    }
+
+    // Whether the pending press is a hold, from the events that came after it.
+    // None while it cannot be told yet.
+    fn decide(&self, press: &KeyEvent, now: u64, events: &EventQueue) -> Option<bool> {
+        let deadline = press.timestamp + self.config.tapping_term_us;
+
+        for (i, event) in events.iter().enumerate() {
+            if event.timestamp >= deadline {
+                return Some(true);
+            }
+            if (event.row, event.col) == (press.row, press.col) {
+                return Some(false);
+            }
+
+            let hold = match self.config.flavor {
+                Flavor::Timeout => false,
+                Flavor::PermissiveHold => {
+                    !event.pressed
+                        && events.iter().take(i).any(|other| {
+                            other.pressed && (other.row, other.col) == (event.row, event.col)
+                        })
+                }
+                Flavor::HoldOnOtherKeyPress => event.pressed,
+            };
+            if hold {
+                return Some(true);
+            }
+        }
+
+        (now >= deadline).then_some(true)
+    }
+}
+
+fn resolved(event: KeyEvent, action: Action, prefix: Option<Press>) -> Resolved {
+    Resolved {
+        event,
+        action,
+        synthetic: false,
+        prefix,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::keycode::KeyCode;
+    use crate::keypad::{Layer, Sequence};
+
+    const TAP: Action = Action::Macro(Sequence::Presses(&[&[KeyCode::ESC]]));
+    const HOLD: Action = Action::Keys(&[KeyCode::LEFTCTRL]);
+    const OTHER: Action = Action::Macro(Sequence::Presses(&[&[KeyCode::A]]));
+
+    // A tap-hold key and another key, a layer-tap key onto a layer where
+    // the other key changes, and a tap-dance key
+    const KEYMAP: &[Layer<1, 4>] = &[
+        Layer {
+            prefix: None,
+            keys: [[
+                Action::TapHold(&TapHold {
+                    tap: TAP,
+                    hold: HOLD,
+                }),
+                OTHER,
+                Action::TapHold(&TapHold {
+                    tap: TAP,
+                    hold: Action::Momentary(1),
+                }),
+                Action::TapDance(&TapDance {
+                    taps: &[TAP, OTHER, Action::Record],
+                    holds: &[HOLD, Action::Momentary(1)],
+                }),
+            ]],
+            encoders: &[],
+        },
+        Layer {
+            prefix: None,
+            keys: [[
+                Action::Transparent,
+                Action::Record,
+                Action::Transparent,
+                Action::Transparent,
+            ]],
+            encoders: &[],
+        },
+    ];
+
+    const TERM: u64 = 200;
+
+    fn config(flavor: Flavor, retro_tapping: bool) -> TapHoldConfig {
+        TapHoldConfig {
+            tapping_term_us: TERM,
+            flavor,
+            retro_tapping,
+            tap_dance_term_us: TERM,
+            combo_term_us: 50,
+        }
+    }
+
+    fn event(col: u8, pressed: bool, timestamp: u64) -> KeyEvent {
+        KeyEvent {
+            row: 0,
+            col,
+            pressed,
+            timestamp,
+        }
+    }
+
+    // Push the events, then return the actions resolved at `now`
+    fn run(
+        keys: &mut TapHoldKeys<1, 4>,
+        layers: &mut Layers<1, 4>,
+        events: &[KeyEvent],
+        now: u64,
+    ) -> heapless::Vec<(u8, bool, &'static str), 12> {
+        let mut queue = EventQueue::new();
+        for &event in events {
+            queue.push_back(event).unwrap();
+        }
+
+        let mut actions = heapless::Vec::new();
+        while let Some(resolved) = keys.next(now, &mut queue, layers, false) {
+            let name = match resolved.action {
+                Action::Keys(_) => "hold",
+                Action::Macro(Sequence::Presses(&[&[KeyCode::ESC]])) => "tap",
+                Action::Macro(_) => "other",
+                Action::Momentary(_) => "layer",
+                Action::Record => "layer other",
+                _ => "none",
+            };
+            actions
+                .push((resolved.event.col, resolved.event.pressed, name))
+                .unwrap();
+        }
+        actions
+    }
+
+    #[test]
+    fn released_within_the_term_is_a_tap() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        assert!(run(&mut keys, &mut layers, &[event(0, true, 0)], 100).is_empty());
+        assert_eq!(
+            run(&mut keys, &mut layers, &[event(0, false, 150)], 150),
+            [(0, true, "tap"), (0, false, "tap")]
+        );
+    }
+
+    #[test]
+    fn held_past_the_term_is_a_hold() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        assert!(run(&mut keys, &mut layers, &[event(0, true, 0)], TERM - 1).is_empty());
+        assert_eq!(run(&mut keys, &mut layers, &[], TERM), [(0, true, "hold")]);
+        assert_eq!(
+            run(&mut keys, &mut layers, &[event(0, false, 300)], 300),
+            [(0, false, "hold")]
+        );
+    }
+
+    #[test]
+    fn other_keys_wait_for_the_decision() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        // Pressed and released within the term: a tap, then the other key
+        let events = [
+            event(0, true, 0),
+            event(1, true, 50),
+            event(1, false, 80),
+            event(0, false, 100),
+        ];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 100),
+            [
+                (0, true, "tap"),
+                (1, true, "other"),
+                (1, false, "other"),
+                (0, false, "tap")
+            ]
+        );
+    }
+
+    #[test]
+    fn permissive_hold_holds_on_another_tap() {
+        let mut keys = TapHoldKeys::new(config(Flavor::PermissiveHold, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        // Another key pressed only: still undecided
+        let events = [event(0, true, 0), event(1, true, 50)];
+        assert!(run(&mut keys, &mut layers, &events, 60).is_empty());
+
+        // Then released before the tap-hold key: a hold
+        let mut keys = TapHoldKeys::new(config(Flavor::PermissiveHold, false), &[]);
+        let events = [event(0, true, 0), event(1, true, 50), event(1, false, 80)];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 80),
+            [(0, true, "hold"), (1, true, "other"), (1, false, "other")]
+        );
+    }
+
+    #[test]
+    fn hold_on_other_key_press_holds_right_away() {
+        let mut keys = TapHoldKeys::new(config(Flavor::HoldOnOtherKeyPress, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        let events = [event(0, true, 0), event(1, true, 50)];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 50),
+            [(0, true, "hold"), (1, true, "other")]
+        );
+    }
+
+    #[test]
+    fn layer_tap_resolves_later_keys_on_its_layer() {
+        let mut keys = TapHoldKeys::new(config(Flavor::HoldOnOtherKeyPress, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        let events = [
+            event(2, true, 0),
+            event(1, true, 50),
+            event(2, false, 60),
+            event(1, false, 70),
+        ];
+        // The other key is released on the layer it was pressed on
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 70),
+            [
+                (2, true, "layer"),
+                (1, true, "layer other"),
+                (2, false, "layer"),
+                (1, false, "layer other")
+            ]
+        );
+    }
+
+    #[test]
+    fn retro_tapping_taps_a_lone_hold() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, true), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        let events = [event(0, true, 0), event(0, false, 300)];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 300),
+            [
+                (0, true, "hold"),
+                (0, false, "hold"),
+                (0, true, "tap"),
+                (0, false, "tap")
+            ]
+        );
+
+        // Not once another key was pressed
+        let events = [
+            event(0, true, 400),
+            event(1, true, 700),
+            event(1, false, 750),
+            event(0, false, 800),
+        ];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 800),
+            [
+                (0, true, "hold"),
+                (1, true, "other"),
+                (1, false, "other"),
+                (0, false, "hold")
+            ]
+        );
+    }
+
+    #[test]
+    fn retro_tap_applies_its_layer_key() {
+        const TOGGLE: &[Layer<1, 4>] = &[
+            Layer {
+                prefix: None,
+                keys: [[
+                    Action::TapHold(&TapHold {
+                        tap: Action::Toggle(1),
+                        hold: HOLD,
+                    }),
+                    OTHER,
+                    Action::None,
+                    Action::None,
+                ]],
+                encoders: &[],
+            },
+            Layer {
+                prefix: None,
+                keys: [[
+                    Action::Transparent,
+                    Action::Record,
+                    Action::Transparent,
+                    Action::Transparent,
+                ]],
+                encoders: &[],
+            },
+        ];
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, true), &[]);
+        let mut layers = Layers::new(TOGGLE);
+
+        let events = [event(0, true, 0), event(0, false, 300)];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 300),
+            [
+                (0, true, "hold"),
+                (0, false, "hold"),
+                (0, true, "none"),
+                (0, false, "none")
+            ]
+        );
+        assert_eq!(
+            run(&mut keys, &mut layers, &[event(1, true, 400)], 400),
+            [(1, true, "layer other")]
+        );
+    }
+
+    #[test]
+    fn tap_dance_counts_taps_within_the_term() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        // Each tap starts a new term
+        let events = [
+            event(3, true, 0),
+            event(3, false, 50),
+            event(3, true, 150),
+            event(3, false, 200),
+        ];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 399),
+            [
+                (3, true, "none"),
+                (3, false, "none"),
+                (3, true, "none"),
+                (3, false, "none")
+            ]
+        );
+        assert_eq!(
+            run(&mut keys, &mut layers, &[], 400),
+            [(3, true, "other"), (3, false, "other")]
+        );
+    }
+
+    #[test]
+    fn tap_dance_ends_on_another_key() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        let events = [event(3, true, 0), event(3, false, 50), event(1, true, 60)];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 60),
+            [
+                (3, true, "none"),
+                (3, false, "none"),
+                (3, true, "tap"),
+                (3, false, "tap"),
+                (1, true, "other")
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_ends_at_its_last_tap() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        // No action for a fourth tap, the third one ends it without waiting
+        let events = [
+            event(3, true, 0),
+            event(3, false, 50),
+            event(3, true, 100),
+            event(3, false, 150),
+            event(3, true, 200),
+            event(3, false, 250),
+        ];
+        assert_eq!(
+            run(&mut keys, &mut layers, &events, 250),
+            [
+                (3, true, "none"),
+                (3, false, "none"),
+                (3, true, "none"),
+                (3, false, "none"),
+                (3, true, "none"),
+                (3, false, "none"),
+                (3, true, "layer other"),
+                (3, false, "layer other")
+            ]
+        );
+    }
+
+    #[test]
+    fn tap_dance_held_on_a_tap_does_its_hold_action() {
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(KEYMAP);
+
+        // Tapped then held: the layer, released with the key
+        let events = [event(3, true, 0), event(3, false, 50), event(3, true, 100)];
+        run(&mut keys, &mut layers, &events, 100);
+        assert_eq!(
+            run(&mut keys, &mut layers, &[event(1, true, 400)], 400),
+            [(3, true, "layer"), (1, true, "layer other")]
+        );
+        assert_eq!(
+            run(&mut keys, &mut layers, &[event(3, false, 500)], 500),
+            [(3, false, "layer")]
+        );
+    }
+
+    #[test]
+    fn presses_of_a_leader_sequence_change_no_layer() {
+        const LAYER_KEYS: &[Layer<1, 4>] = &[
+            Layer {
+                prefix: None,
+                keys: [[Action::Toggle(1), OTHER, Action::None, Action::None]],
+                encoders: &[],
+            },
+            Layer {
+                prefix: None,
+                keys: [[
+                    Action::Transparent,
+                    Action::Record,
+                    Action::Transparent,
+                    Action::Transparent,
+                ]],
+                encoders: &[],
+            },
+        ];
+        let mut keys = TapHoldKeys::new(config(Flavor::Timeout, false), &[]);
+        let mut layers = Layers::new(LAYER_KEYS);
+
+        let mut queue = EventQueue::new();
+        queue.push_back(event(0, true, 0)).unwrap();
+        queue.push_back(event(0, false, 50)).unwrap();
+        let press = keys.next(50, &mut queue, &mut layers, true).unwrap();
+        assert!(matches!(press.action, Action::None));
+        // Released after the sequence ended
+        let release = keys.next(50, &mut queue, &mut layers, false).unwrap();
+        assert!(matches!(release.action, Action::None));
+
+        queue.push_back(event(1, true, 100)).unwrap();
+        let other = keys.next(100, &mut queue, &mut layers, false).unwrap();
+        assert!(matches!(other.action, Action::Macro(_)));
+    }
+}
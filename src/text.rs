@@ -0,0 +1,82 @@
+// Type ASCII strings from macros, on a host set to HOST_LAYOUT.
+// Strings are checked at compile time, so a macro holding a character
+// that cannot be typed on that layout does not build.
+use crate::keycode::KeyCode;
+use crate::keypad::Press;
+use crate::layout::typed;
+use crate::HOST_LAYOUT;
+
+// A string that only holds characters that can be typed.
+// Built with `Text::new` in a const, e.g. `Step::Text(Text::new("hello"))`.
+#[derive(Clone, Copy)]
+pub struct Text(&'static str);
+
+impl Text {
+    pub const fn new(text: &'static str) -> Self {
+        let bytes = text.as_bytes();
+        let mut i = 0;
+        while i < bytes.len() {
+            if typed(HOST_LAYOUT, bytes[i]).is_none() {
+                panic!("text holds a character that cannot be typed");
+            }
+            i += 1;
+        }
+        Self(text)
+    }
+
+    // The keys to tap to type the character at an index
+    pub fn press(&self, index: usize) -> Option<Press> {
+        self.0.as_bytes().get(index).map(|&c| char_press(c))
+    }
+}
+
+// Shift, AltGr, then the key of every ASCII character on the host layout.
+// Modifiers are packed next to the key so each character has a press to point to.
+static ASCII_PRESSES: [[KeyCode; 3]; 128] = {
+    let mut presses = [[KeyCode::NONE; 3]; 128];
+    let mut c = 0;
+    while c < 128 {
+        if let Some(typed) = typed(HOST_LAYOUT, c as u8) {
+            presses[c] = match (typed.shift, typed.altgr) {
+                (false, false) => [KeyCode::NONE, KeyCode::NONE, typed.key],
+                (true, false) => [KeyCode::NONE, KeyCode::LEFTSHIFT, typed.key],
+                (false, true) => [KeyCode::NONE, KeyCode::RIGHTALT, typed.key],
+                (true, true) => [KeyCode::LEFTSHIFT, KeyCode::RIGHTALT, typed.key],
+            };
+        }
+        c += 1;
+    }
+    presses
+};
+
+// The keys to tap to type an ASCII character that can be typed on the host layout
+pub fn char_press(c: u8) -> Press {
+    let press = &ASCII_PRESSES[c as usize];
+    let start = press
+        .iter()
+        .take_while(|&&key| key == KeyCode::NONE)
+        .count();
+    &press[start..]
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // HOST_LAYOUT is US
+    #[test]
+    fn characters_are_typed_with_shift_when_needed() {
+        let text = Text::new("hI!\n");
+        assert!(text.press(0) == Some(&[KeyCode::H][..]));
+        assert!(text.press(1) == Some(&[KeyCode::LEFTSHIFT, KeyCode::I][..]));
+        assert!(text.press(2) == Some(&[KeyCode::LEFTSHIFT, KeyCode::NUM1][..]));
+        assert!(text.press(3) == Some(&[KeyCode::ENTER][..]));
+        assert!(text.press(4).is_none());
+    }
+
+    #[test]
+    fn only_printable_ascii_can_be_typed() {
+        assert!(typed(HOST_LAYOUT, 0x7F).is_none());
+        assert!(typed(HOST_LAYOUT, 0x07).is_none());
+    }
+}
@@ -0,0 +1,186 @@
+// Type Unicode characters through the input method of the host.
+// Each character becomes a list of steps: the key sequence starting the
+// input method, the hex digits of the character, then the key ending it.
+use crate::keycode::KeyCode;
+use crate::keypad::{Press, Step};
+use crate::text::char_press;
+
+#[allow(unused)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum UnicodeMode {
+    // Ctrl+Shift+U, hex digits, Space (IBus, GTK)
+    Linux,
+    // Compose key (Right Alt by default), U, hex digits, Enter
+    WinCompose,
+    // Hold Alt, keypad +, hex digits, release Alt.
+    // Needs EnableHexNumpad set in the registry.
+    WindowsAltCodes,
+    // Hold Option and type the UTF-16 code units in hex,
+    // with the "Unicode Hex Input" input source selected
+    MacOs,
+}
+
+const LINUX_START: Press = &[KeyCode::LEFTCTRL, KeyCode::LEFTSHIFT, KeyCode::U];
+const COMPOSE: Press = &[KeyCode::RIGHTALT];
+const ALT: Press = &[KeyCode::LEFTALT];
+const KPPLUS: Press = &[KeyCode::KPPLUS];
+const SPACE: Press = &[KeyCode::SPACE];
+const ENTER: Press = &[KeyCode::ENTER];
+
+// Hex digits typed on the numpad for Alt codes
+const NUMPAD_DIGITS: [Press; 10] = [
+    &[KeyCode::KP0],
+    &[KeyCode::KP1],
+    &[KeyCode::KP2],
+    &[KeyCode::KP3],
+    &[KeyCode::KP4],
+    &[KeyCode::KP5],
+    &[KeyCode::KP6],
+    &[KeyCode::KP7],
+    &[KeyCode::KP8],
+    &[KeyCode::KP9],
+];
+
+// The Unicode Hex Input source of macOS has US key positions,
+// whatever the layout of the host
+const US_HEX_DIGITS: [Press; 16] = [
+    &[KeyCode::NUM0],
+    &[KeyCode::NUM1],
+    &[KeyCode::NUM2],
+    &[KeyCode::NUM3],
+    &[KeyCode::NUM4],
+    &[KeyCode::NUM5],
+    &[KeyCode::NUM6],
+    &[KeyCode::NUM7],
+    &[KeyCode::NUM8],
+    &[KeyCode::NUM9],
+    &[KeyCode::A],
+    &[KeyCode::B],
+    &[KeyCode::C],
+    &[KeyCode::D],
+    &[KeyCode::E],
+    &[KeyCode::F],
+];
+
+// Up to two UTF-16 code units of 4 digits for macOS, 6 digits otherwise
+const MAX_DIGITS: usize = 8;
+
+// Hex digits of a character, most significant first
+struct Digits {
+    digits: [u8; MAX_DIGITS],
+    len: usize,
+}
+
+impl Digits {
+    fn new(c: char, mode: UnicodeMode) -> Self {
+        let mut digits = Digits {
+            digits: [0; MAX_DIGITS],
+            len: 0,
+        };
+
+        if mode == UnicodeMode::MacOs {
+            let mut units = [0; 2];
+            for &unit in c.encode_utf16(&mut units).iter() {
+                for shift in [12, 8, 4, 0] {
+                    digits.push((unit >> shift) as u8 & 0xF);
+                }
+            }
+        } else {
+            let code = c as u32;
+            let len = (8 - code.leading_zeros() as usize / 4).max(1);
+            for i in (0..len).rev() {
+                digits.push((code >> (i * 4)) as u8 & 0xF);
+            }
+        }
+
+        digits
+    }
+
+    fn push(&mut self, digit: u8) {
+        self.digits[self.len] = digit;
+        self.len += 1;
+    }
+}
+
+fn digit_press(digit: u8, mode: UnicodeMode) -> Press {
+    match mode {
+        UnicodeMode::MacOs => US_HEX_DIGITS[digit as usize],
+        UnicodeMode::WindowsAltCodes if digit < 10 => NUMPAD_DIGITS[digit as usize],
+        _ => char_press(b"0123456789abcdef"[digit as usize]),
+    }
+}
+
+// The step at an index of the steps typing a character, None past the last one
+pub fn step(c: char, mode: UnicodeMode, index: usize) -> Option<Step> {
+    let (start, end): (&[Step], Step) = match mode {
+        UnicodeMode::Linux => (&[Step::Tap(LINUX_START)], Step::Tap(SPACE)),
+        UnicodeMode::WinCompose => (
+            &[Step::Tap(COMPOSE), Step::Tap(&[KeyCode::U])],
+            Step::Tap(ENTER),
+        ),
+        UnicodeMode::WindowsAltCodes => (&[Step::Down(ALT), Step::Tap(KPPLUS)], Step::Up(ALT)),
+        UnicodeMode::MacOs => (&[Step::Down(ALT)], Step::Up(ALT)),
+    };
+
+    if let Some(&step) = start.get(index) {
+        return Some(step);
+    }
+
+    let digits = Digits::new(c, mode);
+    let index = index - start.len();
+    match index.cmp(&digits.len) {
+        core::cmp::Ordering::Less => Some(Step::Tap(digit_press(digits.digits[index], mode))),
+        core::cmp::Ordering::Equal => Some(end),
+        core::cmp::Ordering::Greater => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn steps(c: char, mode: UnicodeMode) -> usize {
+        (0..).take_while(|&i| step(c, mode, i).is_some()).count()
+    }
+
+    fn tap(c: char, mode: UnicodeMode, index: usize) -> Option<Press> {
+        match step(c, mode, index) {
+            Some(Step::Tap(press)) => Some(press),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn linux_types_the_code_point_between_ctrl_shift_u_and_space() {
+        // U+2192, 4 digits
+        assert_eq!(steps('→', UnicodeMode::Linux), 6);
+        assert_eq!(tap('→', UnicodeMode::Linux, 0), Some(LINUX_START));
+        assert_eq!(tap('→', UnicodeMode::Linux, 1), Some(&[KeyCode::NUM2][..]));
+        assert_eq!(tap('→', UnicodeMode::Linux, 5), Some(SPACE));
+    }
+
+    #[test]
+    fn macos_types_utf16_code_units() {
+        // U+1F600 is the surrogate pair D83D DE00
+        let digits = Digits::new('😀', UnicodeMode::MacOs);
+        assert_eq!(
+            &digits.digits[..digits.len],
+            &[0xD, 0x8, 0x3, 0xD, 0xD, 0xE, 0x0, 0x0]
+        );
+        assert_eq!(steps('😀', UnicodeMode::MacOs), 10);
+    }
+
+    #[test]
+    fn alt_codes_use_the_numpad_for_decimal_digits() {
+        // U+03BB
+        assert_eq!(steps('λ', UnicodeMode::WindowsAltCodes), 6);
+        assert_eq!(
+            tap('λ', UnicodeMode::WindowsAltCodes, 2),
+            Some(&[KeyCode::KP3][..])
+        );
+        assert_eq!(
+            tap('λ', UnicodeMode::WindowsAltCodes, 3),
+            Some(&[KeyCode::B][..])
+        );
+    }
+}